@@ -0,0 +1,16 @@
+use std::fs;
+use std::path::Path;
+
+use crate::utils::{self, must};
+
+// print the certificate of the local ca, which could be used as roots
+pub fn export_ca(dir: String) {
+    must!(
+        utils::load_or_create_ca(&dir),
+        "load local ca from {}",
+        &dir
+    );
+    let path = Path::new(&dir).join("ca.pem");
+    let pem = must!(fs::read_to_string(&path), "read {}", path.display());
+    print!("{}", pem);
+}
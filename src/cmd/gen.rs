@@ -0,0 +1,408 @@
+use std::fs;
+use std::path::Path;
+
+use clap::ArgMatches;
+
+use crate::utils::must;
+use crate::config::GlobalConfig;
+use crate::config::{NetConfig, TransportConfig, TLSConfig};
+use crate::config::{EndpointConfig, EpHalfConfig, MaybeHalfConfig};
+
+#[cfg(feature = "ws")]
+use crate::config::trans::WebSocketConfig;
+#[cfg(feature = "h2c")]
+use crate::config::trans::HTTP2Config;
+#[cfg(feature = "quic")]
+use crate::config::trans::QuicConfig;
+
+pub struct GenOptions {
+    pub proto: String,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+    pub mux: usize,
+    pub local: String,
+    pub forward: String,
+    pub cert: String,
+    pub key: String,
+    pub out: String,
+}
+
+pub fn gen_protocols() -> Vec<&'static str> {
+    #[allow(unused_mut)]
+    let mut protocols = vec!["tcp"];
+    #[cfg(feature = "tls")]
+    protocols.push("tls");
+    #[cfg(feature = "ws")]
+    protocols.push("ws");
+    #[cfg(all(feature = "ws", feature = "tls"))]
+    protocols.push("wss");
+    #[cfg(feature = "h2c")]
+    protocols.push("h2c");
+    #[cfg(all(feature = "h2c", feature = "tls"))]
+    protocols.push("h2");
+    #[cfg(feature = "udp")]
+    protocols.push("udp");
+    #[cfg(feature = "quic")]
+    protocols.push("quic");
+    protocols
+}
+
+impl GenOptions {
+    pub fn from_matches(m: &ArgMatches) -> Self {
+        let value = |name: &str| m.value_of(name).unwrap_or("").to_string();
+        GenOptions {
+            proto: value("proto"),
+            host: value("host"),
+            port: must!(value("port").parse::<u16>(), "parse port"),
+            path: value("path"),
+            mux: must!(value("mux").parse::<usize>(), "parse mux"),
+            local: value("local"),
+            forward: value("forward"),
+            cert: value("cert"),
+            key: value("key"),
+            out: value("out"),
+        }
+    }
+}
+
+pub fn generate(opts: GenOptions) {
+    let out = Path::new(&opts.out);
+    must!(fs::create_dir_all(out), "create {}", &opts.out);
+    let join = |name: &str| out.join(name).to_string_lossy().to_string();
+
+    let (server, client) = generate_pair(&opts, &join);
+    for (name, config) in [("server.json", server), ("client.json", client)] {
+        let path = join(name);
+        let s = serde_json::to_string_pretty(&config).unwrap();
+        must!(fs::write(&path, s), "write {}", &path);
+        println!("generated {}", &path);
+    }
+}
+
+// returns server, client
+#[allow(unused_variables)]
+fn generate_pair(
+    opts: &GenOptions,
+    join: &dyn Fn(&str) -> String,
+) -> (GlobalConfig, GlobalConfig) {
+    let is_tls = matches!(opts.proto.as_str(), "tls" | "wss" | "h2" | "quic");
+
+    // prepare certificate for the server, and roots for the client
+    let (server_tls, client_tls) = if is_tls {
+        #[cfg(feature = "tls")]
+        {
+            let (cert, key, roots) = prepare_cert(opts, join);
+            (new_server_tls(opts, cert, key), new_client_tls(opts, roots))
+        }
+        #[cfg(not(feature = "tls"))]
+        unreachable!()
+    } else {
+        (TLSConfig::None, TLSConfig::None)
+    };
+
+    // the transport carries what the local apps send,
+    // e.g. quic carries tcp streams
+    let server = single_endpoint(
+        EpHalfConfig {
+            addr: format!("0.0.0.0:{}", opts.port),
+            net: new_trans_net(opts),
+            trans: new_trans(opts),
+            tls: server_tls,
+            ..Default::default()
+        },
+        EpHalfConfig {
+            addr: opts.forward.clone(),
+            net: new_payload_net(opts),
+            ..Default::default()
+        },
+    );
+
+    let client = single_endpoint(
+        EpHalfConfig {
+            addr: opts.local.clone(),
+            net: new_payload_net(opts),
+            ..Default::default()
+        },
+        EpHalfConfig {
+            addr: format!("{}:{}", opts.host, opts.port),
+            net: new_trans_net(opts),
+            trans: new_trans(opts),
+            tls: client_tls,
+            ..Default::default()
+        },
+    );
+
+    (server, client)
+}
+
+fn single_endpoint(listen: EpHalfConfig, remote: EpHalfConfig) -> GlobalConfig {
+    GlobalConfig {
+        endpoints: vec![EndpointConfig {
+            listen: MaybeHalfConfig::Config(Box::new(listen)),
+            remote: MaybeHalfConfig::Config(Box::new(remote)),
+            sni_routes: Vec::new(),
+            alpn_routes: Vec::new(),
+            sniff_routes: Vec::new(),
+        }],
+        ..Default::default()
+    }
+}
+
+// net between the client and the server
+fn new_trans_net(opts: &GenOptions) -> NetConfig {
+    match opts.proto.as_str() {
+        #[cfg(feature = "udp")]
+        "udp" | "quic" => NetConfig::UDP,
+        _ => NetConfig::TCP,
+    }
+}
+
+// net of the local apps and the forwarded service
+fn new_payload_net(opts: &GenOptions) -> NetConfig {
+    match opts.proto.as_str() {
+        #[cfg(feature = "udp")]
+        "udp" => NetConfig::UDP,
+        _ => NetConfig::TCP,
+    }
+}
+
+#[allow(unused_variables)]
+fn new_trans(opts: &GenOptions) -> TransportConfig {
+    match opts.proto.as_str() {
+        #[cfg(feature = "ws")]
+        "ws" | "wss" => TransportConfig::WS(WebSocketConfig {
+            path: opts.path.clone(),
+        }),
+        #[cfg(feature = "h2c")]
+        "h2c" | "h2" => TransportConfig::H2(HTTP2Config {
+            path: opts.path.clone(),
+            server_push: false,
+            mux: opts.mux,
+        }),
+        #[cfg(feature = "quic")]
+        "quic" => TransportConfig::QUIC(QuicConfig { mux: opts.mux }),
+        _ => TransportConfig::Plain,
+    }
+}
+
+#[cfg(feature = "tls")]
+use tls_ext::*;
+#[cfg(feature = "tls")]
+mod tls_ext {
+    use super::*;
+    use crate::utils;
+    use crate::config::tls::{TLSClientConfig, TLSServerConfig};
+
+    fn server_name(opts: &GenOptions) -> String {
+        if opts.host.parse::<std::net::IpAddr>().is_ok() {
+            String::from(utils::NOT_A_DNS_NAME)
+        } else {
+            opts.host.clone()
+        }
+    }
+
+    fn alpns(opts: &GenOptions) -> Vec<String> {
+        match opts.proto.as_str() {
+            "wss" => vec![String::from("http/1.1")],
+            "h2" => vec![String::from("h2")],
+            _ => Vec::new(),
+        }
+    }
+
+    // returns cert, key, roots
+    pub fn prepare_cert(
+        opts: &GenOptions,
+        join: &dyn Fn(&str) -> String,
+    ) -> (String, String, String) {
+        if !opts.cert.is_empty() {
+            // signed by a trusted CA
+            return (
+                opts.cert.clone(),
+                opts.key.clone(),
+                String::from("firefox"),
+            );
+        }
+        let (cert, key, roots, pin) = generate_self_signed(opts, join);
+        // or trust it by pinning the public key
+        if let Some(pin) = pin {
+            println!("pin: {}", pin);
+        }
+        (cert, key, roots)
+    }
+
+    // returns cert, key, roots and the spki pin
+    pub fn generate_self_signed(
+        opts: &GenOptions,
+        join: &dyn Fn(&str) -> String,
+    ) -> (String, String, String, Option<String>) {
+        // sni is disabled when connecting to an ip address
+        let (certs, key) = must!(utils::generate_cert_key(&server_name(opts)));
+        let (cert_path, key_path) = (join("cert.pem"), join("key.pem"));
+        // the key goes first, which is never overwritten
+        must!(
+            utils::write_key_file(&key_path, &utils::encode_key_pem(&key)),
+            "write {}",
+            &key_path
+        );
+        let cert_pem = utils::encode_certs_pem(&certs);
+        must!(fs::write(&cert_path, &cert_pem), "write {}", &cert_path);
+        println!("generated {}", &cert_path);
+        println!("generated {}", &key_path);
+        // the self-signed certificate is its own root
+        let roots_path = join("roots.pem");
+        must!(fs::write(&roots_path, &cert_pem), "write {}", &roots_path);
+        println!("generated {}", &roots_path);
+        let pin = spki_pin(&certs[0]);
+        (cert_path, key_path, roots_path, pin)
+    }
+
+    fn spki_pin(cert: &rustls::Certificate) -> Option<String> {
+        let digest = utils::spki_fingerprint(cert)?;
+        let hex: String = digest.iter().map(|x| format!("{:02x}", x)).collect();
+        Some(format!("spki:{}", hex))
+    }
+
+    pub fn new_server_tls(
+        opts: &GenOptions,
+        cert: String,
+        key: String,
+    ) -> TLSConfig {
+        TLSConfig::Server(TLSServerConfig {
+            cert,
+            key,
+            alpns: alpns(opts),
+            ..Default::default()
+        })
+    }
+
+    pub fn new_client_tls(opts: &GenOptions, roots: String) -> TLSConfig {
+        TLSConfig::Client(TLSClientConfig {
+            sni: server_name(opts),
+            alpns: alpns(opts),
+            roots,
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(proto: &str) -> GenOptions {
+        GenOptions {
+            proto: String::from(proto),
+            host: String::from("example.com"),
+            port: 443,
+            path: String::from("/"),
+            mux: 0,
+            local: String::from("127.0.0.1:5000"),
+            forward: String::from("127.0.0.1:8080"),
+            // no certificate is generated
+            cert: String::from("cert.pem"),
+            key: String::from("key.pem"),
+            out: String::new(),
+        }
+    }
+
+    // endpoints of a config written then loaded again
+    fn reload(config: GlobalConfig) -> (EpHalfConfig, EpHalfConfig) {
+        let s = serde_json::to_string_pretty(&config).unwrap();
+        let mut config: GlobalConfig = serde_json::from_str(&s).unwrap();
+        let ep = config.endpoints.remove(0);
+        (ep.listen.into(), ep.remote.into())
+    }
+
+    #[test]
+    fn generate_matching_pairs() {
+        for proto in gen_protocols() {
+            let opts = options(proto);
+            let (server, client) = generate_pair(&opts, &|x| x.to_string());
+            let (server_lis, server_remote) = reload(server);
+            let (client_lis, client_remote) = reload(client);
+
+            let payload = if proto == "udp" { "udp" } else { "tcp" };
+            assert_eq!(client_lis.net.to_string(), payload, "{}", proto);
+            assert_eq!(server_remote.net.to_string(), payload, "{}", proto);
+            assert_eq!(
+                client_remote.net.to_string(),
+                server_lis.net.to_string(),
+                "{}",
+                proto
+            );
+            assert_eq!(
+                client_remote.trans.to_string(),
+                server_lis.trans.to_string(),
+                "{}",
+                proto
+            );
+            let trans = match proto {
+                "ws" | "wss" => "ws",
+                "h2c" | "h2" => "h2c",
+                "quic" => "quic",
+                _ => "raw",
+            };
+            assert_eq!(server_lis.trans.to_string(), trans, "{}", proto);
+            assert!(matches!(client_lis.trans, TransportConfig::Plain));
+            assert!(matches!(server_remote.trans, TransportConfig::Plain));
+
+            #[cfg(feature = "tls")]
+            {
+                let is_tls = matches!(proto, "tls" | "wss" | "h2" | "quic");
+                assert_eq!(
+                    matches!(client_remote.tls, TLSConfig::Client(_)),
+                    is_tls,
+                    "{}",
+                    proto
+                );
+                assert_eq!(
+                    matches!(server_lis.tls, TLSConfig::Server(_)),
+                    is_tls,
+                    "{}",
+                    proto
+                );
+            }
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn generate_self_signed_cert() {
+        use crate::utils;
+        let dir = std::env::temp_dir()
+            .join(format!("midori-gen-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let join = |name: &str| dir.join(name).to_string_lossy().to_string();
+        let mut opts = options("tls");
+        opts.cert = String::new();
+        opts.key = String::new();
+        let (cert, key, roots, pin) = generate_self_signed(&opts, &join);
+
+        // the written pair loads and matches
+        let certs = utils::load_certs(&cert).unwrap();
+        let keys = utils::load_keys(&key, None).unwrap();
+        let sk = rustls::sign::any_supported_type(&keys[0]).unwrap();
+        utils::check_key_pair(&certs[0], sk.as_ref()).unwrap();
+        assert_eq!(utils::load_certs(&roots).unwrap(), certs);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&key).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        // an existing key is not overwritten
+        let res = std::panic::catch_unwind(|| {
+            generate_self_signed(&opts, &join);
+        });
+        assert!(res.is_err());
+        assert_eq!(utils::load_keys(&key, None).unwrap(), keys);
+
+        // the printed pin is the spki digest
+        let digest = utils::spki_fingerprint(&certs[0]).unwrap();
+        let hex: String = digest.iter().map(|x| format!("{:02x}", x)).collect();
+        assert_eq!(pin.unwrap(), format!("spki:{}", hex));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -2,16 +2,28 @@ use clap::{Arg, App, SubCommand};
 use crate::utils::{VERSION, NAV_VERSION};
 
 mod nav;
+mod gen;
+mod schema;
+#[cfg(feature = "tls")]
+mod ca;
 pub use nav::run_navigator;
+pub use gen::{generate, GenOptions};
+pub use schema::print_schema;
+#[cfg(feature = "tls")]
+pub use ca::export_ca;
 
 pub enum CmdInput {
     Config(String),
     Navigate,
+    Schema,
+    Generate(GenOptions),
+    #[cfg(feature = "tls")]
+    ExportCA(String),
     None,
 }
 
 pub fn scan() -> CmdInput {
-    let matches = App::new("Midori")
+    let app = App::new("Midori")
         .version(VERSION)
         .about("A multi-protocol network relay")
         .author("zephyr <i@zephyr.moe>")
@@ -29,12 +41,111 @@ pub fn scan() -> CmdInput {
                 .version(NAV_VERSION)
                 .author("zephyr <i@zephyr.moe>"),
         )
-        .get_matches();
+        .subcommand(
+            SubCommand::with_name("config")
+                .about("Config file utilities")
+                .subcommand(
+                    SubCommand::with_name("schema")
+                        .about("print the json schema of config file"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("gen")
+                .about("Generate a pair of client and server configs")
+                .arg(
+                    Arg::with_name("proto")
+                        .help("transport between client and server")
+                        .possible_values(&gen::gen_protocols())
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("host")
+                        .long("host")
+                        .value_name("domain name or ip")
+                        .help("server address used by the client")
+                        .default_value("localhost"),
+                )
+                .arg(
+                    Arg::with_name("port")
+                        .short("p")
+                        .long("port")
+                        .help("server port")
+                        .default_value("443"),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .long("path")
+                        .help("ws or h2 path")
+                        .default_value("/"),
+                )
+                .arg(
+                    Arg::with_name("mux")
+                        .long("mux")
+                        .help("max concurrent streams of h2 or quic")
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::with_name("local")
+                        .long("local")
+                        .help("client listen address")
+                        .default_value("127.0.0.1:1080"),
+                )
+                .arg(
+                    Arg::with_name("forward")
+                        .long("forward")
+                        .help("server remote address")
+                        .default_value("127.0.0.1:8080"),
+                )
+                .arg(
+                    Arg::with_name("cert")
+                        .long("cert")
+                        .help("server certificate, self-signed if omitted")
+                        .requires("key")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("key")
+                        .long("key")
+                        .help("server private key")
+                        .requires("cert")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("out")
+                        .short("o")
+                        .long("out")
+                        .help("output directory")
+                        .default_value("."),
+                ),
+        );
+    #[cfg(feature = "tls")]
+    let app = app.subcommand(
+        SubCommand::with_name("ca")
+            .about("Export the local ca certificate, created if missing")
+            .arg(
+                Arg::with_name("dir")
+                    .help("directory of the local ca, the same as ca_dir")
+                    .required(true),
+            ),
+    );
+    let matches = app.get_matches();
     if let Some(config) = matches.value_of("config") {
         return CmdInput::Config(config.to_string());
     }
     if matches.subcommand_matches("nav").is_some() {
         return CmdInput::Navigate;
     }
+    if let Some(config) = matches.subcommand_matches("config") {
+        if config.subcommand_matches("schema").is_some() {
+            return CmdInput::Schema;
+        }
+    }
+    if let Some(gen) = matches.subcommand_matches("gen") {
+        return CmdInput::Generate(GenOptions::from_matches(gen));
+    }
+    #[cfg(feature = "tls")]
+    if let Some(ca) = matches.subcommand_matches("ca") {
+        return CmdInput::ExportCA(ca.value_of("dir").unwrap().to_string());
+    }
     CmdInput::None
 }
@@ -1 +1,542 @@
-pub fn run_navigator() { todo!() }
+use std::fs;
+use std::path::Path;
+use std::io::{self, Write, BufRead};
+
+use crate::utils::NAV_VERSION;
+use crate::config::GlobalConfig;
+use crate::config::{NetConfig, TransportConfig, TLSConfig};
+use crate::config::{EndpointConfig, EpHalfConfig, MaybeHalfConfig};
+
+#[cfg(feature = "ws")]
+use crate::config::trans::WebSocketConfig;
+#[cfg(feature = "h2c")]
+use crate::config::trans::HTTP2Config;
+#[cfg(feature = "quic")]
+use crate::config::trans::QuicConfig;
+#[cfg(feature = "tls")]
+use crate::config::tls::{TLSClientConfig, TLSServerConfig};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Side {
+    Listen,
+    Remote,
+}
+
+struct Navigator {
+    path: String,
+    config: GlobalConfig,
+    modified: bool,
+}
+
+pub fn run_navigator() {
+    println!("midori navigator v{}", NAV_VERSION);
+    println!("press enter to keep a value, or \"-\" to clear it");
+    let path = match read_line("config file: ") {
+        Some(x) if !x.is_empty() => x,
+        _ => return,
+    };
+    let config = if Path::new(&path).exists() {
+        let config = fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|x| serde_json::from_str(&x).map_err(|e| e.to_string()));
+        match config {
+            Ok(x) => x,
+            Err(e) => {
+                println!("failed to load {}: {}", &path, e);
+                return;
+            }
+        }
+    } else {
+        println!("create new config {}", &path);
+        GlobalConfig::default()
+    };
+    Navigator {
+        path,
+        config,
+        modified: false,
+    }
+    .run()
+}
+
+impl Navigator {
+    fn run(&mut self) {
+        loop {
+            self.list();
+            let cmd = match read_line(
+                "[a]dd, [e]dit <n>, [d]elete <n>, [p]review, [s]ave, [q]uit: ",
+            ) {
+                Some(x) => x,
+                // ctrl-d, same as quit
+                None => {
+                    println!();
+                    if self.discard() {
+                        return;
+                    }
+                    continue;
+                }
+            };
+            let mut iter = cmd.split_whitespace();
+            let op = iter.next().unwrap_or("");
+            let idx = iter.next().and_then(|x| x.parse::<usize>().ok());
+            match (op, idx) {
+                ("a", _) => self.add(),
+                ("e", Some(n)) if self.has(n) => self.edit(n),
+                ("d", Some(n)) if self.has(n) => self.delete(n),
+                ("p", _) => self.preview(),
+                ("s", _) => self.save(),
+                ("q", _) => {
+                    if self.discard() {
+                        return;
+                    }
+                }
+                _ => println!("invalid command"),
+            }
+        }
+    }
+
+    fn has(&self, n: usize) -> bool { n < self.config.endpoints.len() }
+
+    // also true on eof, nothing could be answered any more
+    fn discard(&self) -> bool {
+        if !self.modified {
+            return true;
+        }
+        match read_line("discard changes? [y/N]: ") {
+            Some(x) => x.eq_ignore_ascii_case("y"),
+            None => true,
+        }
+    }
+
+    fn list(&self) {
+        println!();
+        if self.config.endpoints.is_empty() {
+            println!("no endpoint");
+        }
+        for (i, ep) in self.config.endpoints.iter().enumerate() {
+            println!(
+                "[{}] {} -> {}",
+                i,
+                summary(&ep.listen),
+                summary(&ep.remote)
+            );
+        }
+    }
+
+    fn add(&mut self) {
+        let listen = edit_half(Side::Listen, EpHalfConfig::default());
+        let remote = edit_half(Side::Remote, EpHalfConfig::default());
+        self.config.endpoints.push(EndpointConfig {
+            listen: compact(listen),
+            remote: compact(remote),
+            sni_routes: Vec::new(),
+            alpn_routes: Vec::new(),
+            sniff_routes: Vec::new(),
+        });
+        self.modified = true;
+    }
+
+    fn edit(&mut self, n: usize) {
+        let ep = self.config.endpoints.remove(n);
+        let listen = edit_half(Side::Listen, ep.listen.into());
+        let remote = edit_half(Side::Remote, ep.remote.into());
+        self.config.endpoints.insert(
+            n,
+            // routes are kept as is
+            EndpointConfig {
+                listen: compact(listen),
+                remote: compact(remote),
+                sni_routes: ep.sni_routes,
+                alpn_routes: ep.alpn_routes,
+                sniff_routes: ep.sniff_routes,
+            },
+        );
+        self.modified = true;
+    }
+
+    fn delete(&mut self, n: usize) {
+        if confirm(&format!("delete endpoint [{}]?", n), false) {
+            self.config.endpoints.remove(n);
+            self.modified = true;
+        }
+    }
+
+    fn preview(&self) {
+        println!("{}", serde_json::to_string_pretty(&self.config).unwrap());
+    }
+
+    fn save(&mut self) {
+        let s = serde_json::to_string_pretty(&self.config).unwrap();
+        match fs::write(&self.path, s) {
+            Ok(_) => {
+                println!("saved to {}", &self.path);
+                self.modified = false;
+            }
+            Err(e) => println!("failed to save {}: {}", &self.path, e),
+        }
+    }
+}
+
+fn summary(half: &MaybeHalfConfig) -> String {
+    match half {
+        MaybeHalfConfig::Addr(addr) => addr.clone(),
+        MaybeHalfConfig::Config(c) => {
+            format!("{}[{}|{}|{}]", &c.addr, &c.net, &c.trans, &c.tls)
+        }
+    }
+}
+
+// use the short form if possible
+fn compact(half: EpHalfConfig) -> MaybeHalfConfig {
+    if matches!(half.net, NetConfig::TCP)
+        && matches!(half.trans, TransportConfig::Plain)
+        && matches!(half.tls, TLSConfig::None)
+        && half.dns.is_none()
+    {
+        MaybeHalfConfig::Addr(half.addr)
+    } else {
+        MaybeHalfConfig::Config(Box::new(half))
+    }
+}
+
+// ===== Endpoint Half =====
+fn edit_half(side: Side, half: EpHalfConfig) -> EpHalfConfig {
+    let name = match side {
+        Side::Listen => "listen",
+        Side::Remote => "remote",
+    };
+    println!("--- {} ---", name);
+    let EpHalfConfig {
+        addr,
+        net,
+        trans,
+        tls,
+        dns,
+    } = half;
+    let addr = read_default("addr", &addr);
+
+    let net_changed;
+    let net = {
+        let options = net_options(side);
+        let default = options
+            .iter()
+            .position(|x| *x == net.to_string())
+            .unwrap_or(0);
+        let selected = choose("net", &options, default);
+        net_changed = options[selected] != net.to_string();
+        new_net(options[selected])
+    };
+
+    // a dns remote answers queries by itself
+    if matches!(net, NetConfig::DNS) {
+        return EpHalfConfig {
+            addr,
+            net,
+            dns,
+            ..Default::default()
+        };
+    }
+
+    // only offer the transports that could work with the net
+    let trans = {
+        let options = trans_options(&net);
+        let current = if net_changed {
+            String::new()
+        } else {
+            trans.to_string()
+        };
+        let default = options.iter().position(|x| *x == current).unwrap_or(0);
+        let selected = choose("trans", &options, default);
+        if options[selected] == current {
+            edit_trans(trans)
+        } else {
+            edit_trans(new_trans(options[selected]))
+        }
+    };
+
+    // quic must be used with tls, raw udp could not
+    let tls = {
+        let options = tls_options(&net, &trans);
+        let current = tls.to_string();
+        let default = options.iter().position(|x| *x == current).unwrap_or(0);
+        let selected = choose("tls", &options, default);
+        if options[selected] == current {
+            edit_tls(&net, &trans, tls)
+        } else {
+            edit_tls(&net, &trans, new_tls(side, options[selected]))
+        }
+    };
+
+    // dns settings are kept as is
+    EpHalfConfig {
+        addr,
+        net,
+        trans,
+        tls,
+        dns,
+    }
+}
+
+fn net_options(side: Side) -> Vec<&'static str> {
+    #[allow(unused_mut)]
+    let mut options = vec!["tcp"];
+    #[cfg(all(unix, feature = "uds"))]
+    options.push("uds");
+    #[cfg(feature = "udp")]
+    options.push("udp");
+    if let Side::Remote = side {
+        options.push("dns");
+    }
+    options
+}
+
+fn new_net(name: &str) -> NetConfig {
+    match name {
+        #[cfg(all(unix, feature = "uds"))]
+        "uds" => NetConfig::UDS,
+        #[cfg(feature = "udp")]
+        "udp" => NetConfig::UDP,
+        "dns" => NetConfig::DNS,
+        _ => NetConfig::TCP,
+    }
+}
+
+#[allow(unused_mut)]
+fn trans_options(net: &NetConfig) -> Vec<&'static str> {
+    let mut options = vec!["raw"];
+    match net {
+        #[cfg(feature = "udp")]
+        NetConfig::UDP => {
+            #[cfg(feature = "quic")]
+            options.push("quic");
+        }
+        _ => {
+            #[cfg(feature = "ws")]
+            options.push("ws");
+            #[cfg(feature = "h2c")]
+            options.push("h2c");
+        }
+    }
+    options
+}
+
+fn new_trans(name: &str) -> TransportConfig {
+    match name {
+        #[cfg(feature = "ws")]
+        "ws" => TransportConfig::WS(WebSocketConfig {
+            path: String::from("/"),
+        }),
+        #[cfg(feature = "h2c")]
+        "h2c" => TransportConfig::H2(HTTP2Config {
+            path: String::from("/"),
+            server_push: false,
+            mux: 0,
+        }),
+        #[cfg(feature = "quic")]
+        "quic" => TransportConfig::QUIC(QuicConfig { mux: 0 }),
+        _ => TransportConfig::Plain,
+    }
+}
+
+fn edit_trans(trans: TransportConfig) -> TransportConfig {
+    match trans {
+        #[cfg(feature = "ws")]
+        TransportConfig::WS(c) => TransportConfig::WS(WebSocketConfig {
+            path: read_default("ws path", &c.path),
+        }),
+        #[cfg(feature = "h2c")]
+        TransportConfig::H2(c) => TransportConfig::H2(HTTP2Config {
+            path: read_default("h2 path", &c.path),
+            server_push: c.server_push,
+            mux: read_parsed("h2 mux(0 = default)", c.mux),
+        }),
+        #[cfg(feature = "quic")]
+        TransportConfig::QUIC(c) => TransportConfig::QUIC(QuicConfig {
+            mux: read_parsed("quic mux(0 = default)", c.mux),
+        }),
+        x => x,
+    }
+}
+
+#[allow(unused_variables)]
+fn is_quic(trans: &TransportConfig) -> bool {
+    #[cfg(feature = "quic")]
+    if matches!(trans, TransportConfig::QUIC(_)) {
+        return true;
+    }
+    false
+}
+
+#[cfg(feature = "tls")]
+#[allow(unused_variables)]
+fn is_udp(net: &NetConfig) -> bool {
+    #[cfg(feature = "udp")]
+    if matches!(net, NetConfig::UDP) {
+        return true;
+    }
+    false
+}
+
+#[allow(unused_variables)]
+fn tls_options(net: &NetConfig, trans: &TransportConfig) -> Vec<&'static str> {
+    if is_quic(trans) {
+        return vec!["rustls"];
+    }
+    #[allow(unused_mut)]
+    let mut options = vec!["none"];
+    #[cfg(feature = "tls")]
+    if !is_udp(net) {
+        options.push("rustls");
+    }
+    options
+}
+
+#[allow(unused_variables)]
+fn new_tls(side: Side, name: &str) -> TLSConfig {
+    match name {
+        #[cfg(feature = "tls")]
+        "rustls" if side == Side::Listen => {
+            TLSConfig::Server(TLSServerConfig::default())
+        }
+        #[cfg(feature = "tls")]
+        "rustls" => TLSConfig::Client(TLSClientConfig::default()),
+        _ => TLSConfig::None,
+    }
+}
+
+#[allow(unused_variables)]
+fn edit_tls(
+    net: &NetConfig,
+    trans: &TransportConfig,
+    tls: TLSConfig,
+) -> TLSConfig {
+    match tls {
+        #[cfg(feature = "tls")]
+        TLSConfig::Server(c) => TLSConfig::Server(TLSServerConfig {
+            cert: read_default("cert(equals key = self-signed)", &c.cert),
+            key: read_default("key", &c.key),
+            ca_dir: read_default(
+                "ca dir(empty = self-signed if cert equals key)",
+                &c.ca_dir,
+            ),
+            alpns: read_list("alpns", &c.alpns),
+            versions: read_list("versions(tlsv1.2, tlsv1.3)", &c.versions),
+            // tls over tcp always rejects early data
+            early_data: if is_quic(trans) {
+                read_default(
+                    "early data(empty = wait, accept, reject)",
+                    &c.early_data,
+                )
+            } else {
+                String::new()
+            },
+            ocsp: read_default("ocsp", &c.ocsp),
+            fallback: read_default(
+                "fallback(empty = drop non-tls connections)",
+                &c.fallback,
+            ),
+            client_ca: read_default(
+                "client ca(empty = no client auth)",
+                &c.client_ca,
+            ),
+            // the remote should also be raw tcp or uds
+            #[cfg(target_os = "linux")]
+            ktls: matches!(net, NetConfig::TCP)
+                && matches!(trans, TransportConfig::Plain)
+                && confirm("offload tls to the kernel?", c.ktls),
+            ..c
+        }),
+        #[cfg(feature = "tls")]
+        TLSConfig::Client(c) => TLSConfig::Client(TLSClientConfig {
+            sni: read_default("sni", &c.sni),
+            enable_sni: confirm("enable sni?", c.enable_sni),
+            alpns: read_list("alpns", &c.alpns),
+            versions: read_list("versions(tlsv1.2, tlsv1.3)", &c.versions),
+            roots: read_default("roots(native, firefox, file)", &c.roots),
+            skip_verify: confirm("skip verify?", c.skip_verify),
+            pins: read_list("pins(cert:<sha256>, spki:<sha256>)", &c.pins),
+            enable_early_data: confirm(
+                "enable early data?",
+                c.enable_early_data,
+            ),
+            cert: read_default("client cert", &c.cert),
+            key: read_default("client key", &c.key),
+            ..c
+        }),
+        x => x,
+    }
+}
+
+// ===== Prompt =====
+fn read_line(prompt: &str) -> Option<String> {
+    print!("{}", prompt);
+    let _ = io::stdout().flush();
+    let mut buf = String::new();
+    match io::stdin().lock().read_line(&mut buf) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(buf.trim().to_string()),
+    }
+}
+
+// an empty answer keeps the default, "-" clears it
+fn read_default(prompt: &str, default: &str) -> String {
+    match read_line(&format!("{} [{}]: ", prompt, default)) {
+        Some(x) if x == "-" => String::new(),
+        Some(x) if !x.is_empty() => x,
+        _ => default.to_string(),
+    }
+}
+
+fn read_parsed<T>(prompt: &str, default: T) -> T
+where
+    T: std::str::FromStr + ToString,
+{
+    loop {
+        let s = read_default(prompt, &default.to_string());
+        match s.parse::<T>() {
+            Ok(x) => return x,
+            Err(_) => println!("invalid value: {}", s),
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+fn read_list(prompt: &str, default: &[String]) -> Vec<String> {
+    read_default(prompt, &default.join(", "))
+        .split(',')
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+fn choose(prompt: &str, options: &[&str], default: usize) -> usize {
+    if options.len() == 1 {
+        println!("{}: {}", prompt, options[0]);
+        return 0;
+    }
+    let hint = options
+        .iter()
+        .enumerate()
+        .map(|(i, x)| format!("{}.{}", i, x))
+        .collect::<Vec<String>>()
+        .join(" ");
+    loop {
+        let s =
+            read_default(&format!("{} ({})", prompt, hint), options[default]);
+        if let Some(i) = options.iter().position(|x| *x == s) {
+            return i;
+        }
+        match s.parse::<usize>() {
+            Ok(i) if i < options.len() => return i,
+            _ => println!("invalid option: {}", s),
+        }
+    }
+}
+
+fn confirm(prompt: &str, default: bool) -> bool {
+    let hint = if default { "Y/n" } else { "y/N" };
+    match read_line(&format!("{} [{}]: ", prompt, hint)) {
+        Some(x) if x.eq_ignore_ascii_case("y") => true,
+        Some(x) if x.eq_ignore_ascii_case("n") => false,
+        _ => default,
+    }
+}
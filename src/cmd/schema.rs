@@ -0,0 +1,8 @@
+use schemars::schema_for;
+use crate::config::GlobalConfig;
+
+pub fn print_schema() {
+    // only contains the types enabled by cargo features
+    let schema = schema_for!(GlobalConfig);
+    println!("{}", serde_json::to_string_pretty(&schema).unwrap());
+}
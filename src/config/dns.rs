@@ -1,9 +1,11 @@
 use serde::{Serialize, Deserialize};
-use trust_dns_resolver::config::LookupIpStrategy;
+use schemars::JsonSchema;
+use std::time::Duration;
+use trust_dns_resolver::config::{LookupIpStrategy, ResolverOpts};
 use trust_dns_resolver::config::{NameServerConfig, Protocol};
-use std::net::ToSocketAddrs;
+use std::net::{SocketAddr, ToSocketAddrs};
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum DnsMode {
     /// Only query for A (Ipv4) records
@@ -13,15 +15,12 @@ pub enum DnsMode {
     /// Query for A and AAAA in parallel
     Ipv4AndIpv6,
     /// Query for Ipv4 if that fails, query for Ipv6 (default)
+    #[default]
     Ipv4ThenIpv6,
     /// Query for Ipv6 if that fails, query for Ipv4
     Ipv6ThenIpv4,
 }
 
-impl Default for DnsMode {
-    fn default() -> Self { Self::Ipv4ThenIpv6 }
-}
-
 impl From<DnsMode> for LookupIpStrategy {
     fn from(mode: DnsMode) -> Self {
         match mode {
@@ -34,18 +33,98 @@ impl From<DnsMode> for LookupIpStrategy {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum DnsServers {
+    /// system(default), cloudflare, google, quad9
+    Preset(String),
+    Custom(Vec<DnsServerNode>),
+}
+
+impl Default for DnsServers {
+    fn default() -> Self { Self::Preset(String::from("system")) }
+}
+
+/// resolver of an endpoint, unset fields fall back to the global ones
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct DnsConfig {
+    #[serde(default)]
+    pub mode: Option<DnsMode>,
+
+    #[serde(default)]
+    pub servers: Option<DnsServers>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct DnsCacheConfig {
+    /// max number of cached records
+    #[serde(default = "def_cache_size")]
+    pub size: usize,
+
+    /// min and max ttl(s) of positive responses
+    #[serde(default)]
+    pub min_ttl: Option<u64>,
+    #[serde(default)]
+    pub max_ttl: Option<u64>,
+
+    /// min and max ttl(s) of negative responses
+    #[serde(default)]
+    pub negative_min_ttl: Option<u64>,
+    #[serde(default)]
+    pub negative_max_ttl: Option<u64>,
+
+    /// interval(s) to re-resolve the remote of a mux connection,
+    /// which is retired if the answer changes. 0 = disabled
+    #[serde(default = "def_refresh_interval")]
+    pub refresh_interval: u64,
+}
+
+impl Default for DnsCacheConfig {
+    fn default() -> Self {
+        DnsCacheConfig {
+            size: def_cache_size(),
+            min_ttl: None,
+            max_ttl: None,
+            negative_min_ttl: None,
+            negative_max_ttl: None,
+            refresh_interval: def_refresh_interval(),
+        }
+    }
+}
+
+impl DnsCacheConfig {
+    pub fn apply_to_opts(&self, opts: ResolverOpts) -> ResolverOpts {
+        let secs = |x: Option<u64>| x.map(Duration::from_secs);
+        ResolverOpts {
+            cache_size: self.size,
+            positive_min_ttl: secs(self.min_ttl),
+            positive_max_ttl: secs(self.max_ttl),
+            negative_min_ttl: secs(self.negative_min_ttl),
+            negative_max_ttl: secs(self.negative_max_ttl),
+            ..opts
+        }
+    }
+}
+
 // default values
 const fn def_true() -> bool { true }
+const fn def_cache_size() -> usize { 32 }
+const fn def_refresh_interval() -> u64 { 60 }
 fn default_protocol() -> String { String::from("udp") }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub struct DnsServerNode {
     addr: String,
 
+    /// udp, tcp, tls(dns over tls), https(dns over https)
     #[serde(default = "default_protocol")]
     protocol: String,
 
+    /// server name to verify, only used by tls and https
+    #[serde(default)]
+    server_name: String,
+
     #[serde(default = "def_true")]
     trust_nx_responses: bool,
 }
@@ -61,15 +140,42 @@ impl From<DnsServerNode> for NameServerConfig {
 
         let str_protocol = dns_server_node.protocol.as_str();
         let dest_protocol = match str_protocol {
+            "udp" => Protocol::Udp,
             "tcp" => Protocol::Tcp,
-            _ => Protocol::Udp,
+            #[cfg(feature = "tls")]
+            "tls" => Protocol::Tls,
+            #[cfg(feature = "tls")]
+            "https" => Protocol::Https,
+            _ => panic!("unknown dns protocol: {}", str_protocol),
+        };
+        let dest_tls_dns_name = if dest_protocol.is_encrypted() {
+            Some(tls_dns_name(&dns_server_node))
+        } else {
+            None
         };
         let dest_trust_nx_responses = dns_server_node.trust_nx_responses;
         NameServerConfig {
             socket_addr: dns_server_socket,
             protocol: dest_protocol,
-            tls_dns_name: None,
+            tls_dns_name: dest_tls_dns_name,
             trust_nx_responses: dest_trust_nx_responses,
+            #[cfg(feature = "tls")]
+            tls_config: None,
         }
     }
 }
+
+// use the host of addr if server name is not provided
+fn tls_dns_name(dns_server_node: &DnsServerNode) -> String {
+    if !dns_server_node.server_name.is_empty() {
+        return dns_server_node.server_name.clone();
+    }
+    // an ip address, e.g. 1.1.1.1:853 or [2606:4700::1111]:853
+    if dns_server_node.addr.parse::<SocketAddr>().is_ok() {
+        panic!("server name is required by {}", &dns_server_node.addr);
+    }
+    match dns_server_node.addr.rsplit_once(':').map(|x| x.0) {
+        Some(host) if !host.is_empty() => host.to_string(),
+        _ => panic!("server name is required by {}", &dns_server_node.addr),
+    }
+}
@@ -1,10 +1,12 @@
 use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
 
+use super::dns::DnsConfig;
 use super::net::NetConfig;
 use super::tls::TLSConfig;
 use super::trans::TransportConfig;
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema)]
 pub struct EpHalfConfig {
     pub addr: String,
 
@@ -16,19 +18,77 @@ pub struct EpHalfConfig {
 
     #[serde(default)]
     pub tls: TLSConfig,
+
+    /// override the global resolver when connecting to this endpoint
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dns: Option<DnsConfig>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub enum MaybeHalfConfig {
     Addr(String),
-    Config(EpHalfConfig),
+    Config(Box<EpHalfConfig>),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct EndpointConfig {
     pub listen: MaybeHalfConfig,
     pub remote: MaybeHalfConfig,
+
+    /// route tls connections by sni without decrypting them,
+    /// the others go to remote
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sni_routes: Vec<SniRouteConfig>,
+
+    /// route by the negotiated alpn after the tls handshake,
+    /// the others go to listen.trans and remote
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alpn_routes: Vec<AlpnRouteConfig>,
+
+    /// route by the protocol told from the first bytes,
+    /// the others go to remote
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sniff_routes: Vec<SniffRouteConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct SniRouteConfig {
+    /// exact names, or wildcards like *.example.com
+    pub server_names: Vec<String>,
+
+    /// the client should also offer one of them, empty = any
+    #[serde(default)]
+    pub alpns: Vec<String>,
+
+    pub remote: MaybeHalfConfig,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct AlpnRouteConfig {
+    /// must be advertised by listen.tls
+    pub alpns: Vec<String>,
+
+    /// transport after the tls handshake
+    #[serde(default)]
+    pub trans: TransportConfig,
+
+    pub remote: MaybeHalfConfig,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct SniffRouteConfig {
+    /// tls, h2c, http(upgrade request)
+    pub protocol: String,
+
+    /// the chain of this protocol
+    #[serde(default)]
+    pub trans: TransportConfig,
+
+    #[serde(default)]
+    pub tls: TLSConfig,
+
+    pub remote: MaybeHalfConfig,
 }
 
 impl From<MaybeHalfConfig> for EpHalfConfig {
@@ -38,7 +98,7 @@ impl From<MaybeHalfConfig> for EpHalfConfig {
                 addr: s,
                 ..Default::default()
             },
-            MaybeHalfConfig::Config(c) => c,
+            MaybeHalfConfig::Config(c) => *c,
         }
     }
 }
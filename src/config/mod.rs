@@ -1,6 +1,9 @@
 use std::fs;
 use std::sync::Arc;
+use std::net::IpAddr;
+use std::collections::HashMap;
 use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
 
 use crate::utils::must;
 use crate::transport::{AsyncConnect, AsyncAccept};
@@ -12,18 +15,32 @@ pub mod tls;
 pub mod trans;
 
 // re-export
-pub use self::dns::{DnsMode, DnsServerNode};
+pub use self::dns::{DnsMode, DnsServers, DnsCacheConfig};
 pub use net::NetConfig;
 pub use tls::TLSConfig;
 pub use trans::TransportConfig;
-pub use ep::{EndpointConfig, EpHalfConfig, MaybeHalfConfig};
+pub use ep::{
+    EndpointConfig, EpHalfConfig, MaybeHalfConfig, SniRouteConfig,
+    AlpnRouteConfig, SniffRouteConfig,
+};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema)]
 pub struct GlobalConfig {
     #[serde(default)]
     pub dns_mode: DnsMode,
     pub endpoints: Vec<EndpointConfig>,
-    pub dns_servers: Vec<DnsServerNode>,
+    #[serde(default)]
+    pub dns_servers: DnsServers,
+    /// cache size and ttl bounds of the resolvers,
+    /// and re-resolution of mux connections
+    #[serde(default)]
+    pub dns_cache: DnsCacheConfig,
+    /// static host mappings, looked up before any query is made
+    #[serde(default)]
+    pub hosts: HashMap<String, Vec<IpAddr>>,
+    /// connection attempt delay(ms) of happy eyeballs, default 250, min 10
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub happy_eyeballs_delay: Option<u64>,
 }
 
 impl GlobalConfig {
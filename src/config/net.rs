@@ -1,19 +1,19 @@
 use std::fmt::{Display, Formatter};
 use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
 
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum NetConfig {
+    #[default]
     TCP,
     #[cfg(feature = "udp")]
     UDP,
     #[cfg(all(unix, feature = "uds"))]
     UDS,
-}
-
-impl Default for NetConfig {
-    fn default() -> Self { Self::TCP }
+    /// answer dns queries, only used by remote
+    DNS,
 }
 
 impl Display for NetConfig {
@@ -27,6 +27,8 @@ impl Display for NetConfig {
 
             #[cfg(all(unix, feature = "uds"))]
             UDS => write!(f, "uds"),
+
+            DNS => write!(f, "dns"),
         }
     }
 }
@@ -41,6 +43,7 @@ impl NetConfig {
             UDP => false,
             #[cfg(feature = "uds")]
             UDS => true,
+            DNS => false,
         }
     }
 }
@@ -1,9 +1,12 @@
 use std::fmt::{Display, Formatter};
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Deserialize, Deserializer};
+use serde::de::Error as _;
+use schemars::JsonSchema;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, JsonSchema)]
 #[serde(untagged)]
 pub enum TLSConfig {
+    #[default]
     None,
     #[cfg(feature = "tls")]
     Client(TLSClientConfig),
@@ -11,8 +14,22 @@ pub enum TLSConfig {
     Server(TLSServerConfig),
 }
 
-impl Default for TLSConfig {
-    fn default() -> Self { Self::None }
+// untagged, a client or a server is told by the fields,
+// instead of the first variant that could be parsed
+impl<'de> Deserialize<'de> for TLSConfig {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        if value.is_null() {
+            return Ok(TLSConfig::None);
+        }
+        #[cfg(feature = "tls")]
+        return tls_from_value(value).map_err(D::Error::custom);
+        #[cfg(not(feature = "tls"))]
+        Err(D::Error::custom("tls is not enabled"))
+    }
 }
 
 impl Display for TLSConfig {
@@ -34,15 +51,33 @@ pub use enable_tls::*;
 pub mod enable_tls {
     use super::*;
     use std::fs;
-    use std::io::{BufReader, Read};
-    use std::sync::Arc;
+    use std::io::{self, BufReader, Read, Write};
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::time::{Duration, SystemTime};
+    use std::collections::{HashMap, HashSet};
+
+    use log::{info, warn};
+    use lazy_static::lazy_static;
+    use futures::future::{self, Either};
 
     use webpki::DNSNameRef;
-    use rustls::{ClientConfig, ServerConfig, NoClientAuth};
+    use rustls::{ClientConfig, ServerConfig, RootCertStore};
+    use rustls::{
+        NoClientAuth, AllowAnyAuthenticatedClient,
+        AllowAnyAnonymousOrAuthenticatedClient,
+    };
+    use rustls::{ResolvesServerCert, ClientHello, Ticketer};
+    use rustls::{ClientSessionMemoryCache, NoClientSessionStorage};
+    use rustls::{ServerSessionMemoryCache, NoServerSessionStorage};
+    use rustls::sign::{self, CertifiedKey};
     use rustls::internal::msgs::enums::ProtocolVersion;
 
-    use crate::utils::{self, must, CommonAddr, NATIVE_CERTS, NOT_A_DNS_NAME};
-    use crate::transport::tls;
+    use crate::utils::{self, must, CommonAddr, ClientAuth};
+    use crate::utils::{NATIVE_CERTS, NOT_A_DNS_NAME};
+    use crate::error::cert::CertError;
+    use crate::dns;
+    use crate::relay::common::parse_socket_addr;
+    use crate::transport::{tls, plain};
     use crate::transport::{AsyncConnect, AsyncAccept};
 
     // default values
@@ -50,9 +85,59 @@ pub mod enable_tls {
 
     fn def_roots_str() -> String { "firefox".to_string() }
 
+    fn def_client_auth_str() -> String { "required".to_string() }
+
+    fn def_client_session_cache() -> usize { 32 }
+
+    fn def_server_session_cache() -> usize { 256 }
+
+    // only used by a client, the others are shared or server only
+    const CLIENT_FIELDS: &[&str] = &[
+        "skip_verify",
+        "enable_sni",
+        "enable_early_data",
+        "sni",
+        "roots",
+        "pins",
+    ];
+
+    // "role" is told first, otherwise a server has certificates
+    // and no client field, e.g. a client presenting a certificate
+    // also sets sni, or the role
+    pub(super) fn tls_from_value(
+        mut value: serde_json::Value,
+    ) -> serde_json::Result<TLSConfig> {
+        use serde::de::Error;
+        let role = match value.as_object_mut().and_then(|x| x.remove("role")) {
+            Some(serde_json::Value::String(x)) => Some(x),
+            Some(x) => {
+                return Err(Error::custom(format!("invalid tls role: {}", x)))
+            }
+            None => None,
+        };
+        let is_server = match (role.as_deref(), &value) {
+            (Some("server"), _) => true,
+            (Some("client"), _) => false,
+            (Some(x), _) => {
+                return Err(Error::custom(format!("invalid tls role: {}", x)))
+            }
+            (None, serde_json::Value::Object(x)) => {
+                (x.contains_key("cert") || x.contains_key("certs"))
+                    && !CLIENT_FIELDS.iter().any(|k| x.contains_key(*k))
+            }
+            (None, _) => false,
+        };
+        if is_server {
+            TLSServerConfig::deserialize(value).map(TLSConfig::Server)
+        } else {
+            TLSClientConfig::deserialize(value).map(TLSConfig::Client)
+        }
+    }
+
     // TLS Client
-    #[derive(Debug, Serialize, Deserialize)]
+    #[derive(Debug, Serialize, Deserialize, JsonSchema)]
     pub struct TLSClientConfig {
+        #[serde(default)]
         pub skip_verify: bool,
 
         #[serde(default = "def_true")]
@@ -67,13 +152,68 @@ pub mod enable_tls {
         #[serde(default)]
         pub alpns: Vec<String>,
 
-        // tlsv1.2, tlsv1.3
+        /// tlsv1.2, tlsv1.3
         #[serde(default)]
         pub versions: Vec<String>,
 
-        // native, firefox, or provide a file
+        /// e.g. TLS13_AES_128_GCM_SHA256, in order of preference
+        #[serde(default)]
+        pub ciphersuites: Vec<String>,
+
+        /// sessions kept for resumption, 0 = disable
+        #[serde(default = "def_client_session_cache")]
+        pub session_cache: usize,
+
+        /// native, firefox, or provide a file
         #[serde(default = "def_roots_str")]
         pub roots: String,
+
+        /// client certificate, presented if the server asks for it
+        #[serde(default)]
+        pub cert: String,
+
+        #[serde(default)]
+        pub key: String,
+
+        /// passphrase of an encrypted pkcs8 key
+        #[serde(default)]
+        pub key_passphrase: String,
+
+        /// or read the passphrase from a file
+        #[serde(default)]
+        pub key_passphrase_file: String,
+
+        /// sha256 of the server certificate(cert:<hex>)
+        /// or its public key(spki:<hex>)
+        #[serde(default)]
+        pub pins: Vec<String>,
+
+        /// write tls secrets to this file, for debugging.
+        /// SSLKEYLOGFILE is used if empty
+        #[serde(default)]
+        pub keylog: String,
+    }
+
+    impl Default for TLSClientConfig {
+        fn default() -> Self {
+            TLSClientConfig {
+                skip_verify: false,
+                enable_sni: true,
+                enable_early_data: false,
+                sni: String::new(),
+                alpns: Vec::new(),
+                versions: Vec::new(),
+                ciphersuites: Vec::new(),
+                session_cache: def_client_session_cache(),
+                roots: def_roots_str(),
+                cert: String::new(),
+                key: String::new(),
+                key_passphrase: String::new(),
+                key_passphrase_file: String::new(),
+                pins: Vec::new(),
+                keylog: String::new(),
+            }
+        }
     }
 
     struct ClientSkipVerify;
@@ -90,6 +230,124 @@ pub mod enable_tls {
         }
     }
 
+    enum Pin {
+        Cert(Vec<u8>),
+        Spki(Vec<u8>),
+    }
+
+    fn parse_pin(s: &str) -> Pin {
+        let (new_pin, hex): (fn(Vec<u8>) -> Pin, &str) =
+            if let Some(x) = s.strip_prefix("spki:") {
+                (Pin::Spki, x)
+            } else {
+                (Pin::Cert, s.strip_prefix("cert:").unwrap_or(s))
+            };
+        // also accept the output of openssl, e.g. AB:CD:..
+        let hex: Vec<u8> = hex.bytes().filter(|x| *x != b':').collect();
+        let digest: Option<Vec<u8>> = hex
+            .chunks(2)
+            .map(|x| {
+                std::str::from_utf8(x)
+                    .ok()
+                    .and_then(|x| u8::from_str_radix(x, 16).ok())
+            })
+            .collect();
+        match digest {
+            Some(x) if x.len() == 32 => new_pin(x),
+            _ => panic!("invalid pin: {}", s),
+        }
+    }
+
+    // Check the server certificate against the pins,
+    // after the chain is validated(unless skip_verify).
+    struct ClientPinVerify {
+        pins: Vec<Pin>,
+        verify_chain: bool,
+    }
+
+    impl rustls::ServerCertVerifier for ClientPinVerify {
+        fn verify_server_cert(
+            &self,
+            roots: &rustls::RootCertStore,
+            presented_certs: &[rustls::Certificate],
+            dns_name: webpki::DNSNameRef<'_>,
+            ocsp_response: &[u8],
+        ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+            if self.verify_chain {
+                rustls::WebPKIVerifier::new().verify_server_cert(
+                    roots,
+                    presented_certs,
+                    dns_name,
+                    ocsp_response,
+                )?;
+            }
+            let cert = presented_certs
+                .first()
+                .ok_or(rustls::TLSError::NoCertificatesPresented)?;
+            let matched = self.pins.iter().any(|pin| match pin {
+                Pin::Cert(x) => *x == utils::cert_fingerprint(cert),
+                Pin::Spki(x) => {
+                    Some(x) == utils::spki_fingerprint(cert).as_ref()
+                }
+            });
+            if matched {
+                Ok(rustls::ServerCertVerified::assertion())
+            } else {
+                Err(rustls::TLSError::General(String::from(
+                    "certificate does not match any pin",
+                )))
+            }
+        }
+    }
+
+    // names of rustls::ALL_CIPHERSUITES
+    fn parse_ciphersuites(
+        names: &[String],
+    ) -> Vec<&'static rustls::SupportedCipherSuite> {
+        names
+            .iter()
+            .map(|name| {
+                *rustls::ALL_CIPHERSUITES
+                    .iter()
+                    .find(|x| {
+                        format!("{:?}", x.suite).eq_ignore_ascii_case(name)
+                    })
+                    .unwrap_or_else(|| panic!("unknown cipher suite: {}", name))
+            })
+            .collect()
+    }
+
+    // Append secrets to a file in NSS key log format,
+    // like rustls::KeyLogFile but with a configured path.
+    struct KeyLogPath(Mutex<fs::File>);
+
+    impl rustls::KeyLog for KeyLogPath {
+        fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+            let hex = |x: &[u8]| -> String {
+                x.iter().map(|x| format!("{:02x}", x)).collect()
+            };
+            let line =
+                format!("{} {} {}\n", label, hex(client_random), hex(secret));
+            if let Err(e) = self.0.lock().unwrap().write_all(line.as_bytes()) {
+                warn!("tls: failed to write keylog, {}", e);
+            }
+        }
+    }
+
+    fn make_key_log(path: &str) -> io::Result<Arc<dyn rustls::KeyLog>> {
+        if path.is_empty() {
+            // does nothing unless SSLKEYLOGFILE is set
+            return Ok(Arc::new(rustls::KeyLogFile::new()));
+        }
+        let file = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .map_err(load_err(path))?;
+        warn!("tls: write secrets to {}", path);
+        Ok(Arc::new(KeyLogPath(Mutex::new(file))))
+    }
+
     impl TLSClientConfig {
         pub fn to_tls(&self) -> ClientConfig { make_client_config(self) }
 
@@ -124,6 +382,7 @@ pub mod enable_tls {
         let mut tlsc = ClientConfig::new();
         tlsc.enable_sni = config.enable_sni;
         tlsc.enable_early_data = config.enable_early_data;
+        tlsc.key_log = must!(make_key_log(&config.keylog));
         // if not specified, use the constructor's default value
         if !config.alpns.is_empty() {
             tlsc.alpn_protocols =
@@ -141,8 +400,41 @@ pub mod enable_tls {
                 })
                 .collect();
         };
-        // skip verify
-        if config.skip_verify {
+        // the same as alpns
+        if !config.ciphersuites.is_empty() {
+            tlsc.ciphersuites = parse_ciphersuites(&config.ciphersuites);
+        };
+        // shared by all connections of the connector
+        tlsc.session_persistence = match config.session_cache {
+            0 => Arc::new(NoClientSessionStorage {}),
+            x => ClientSessionMemoryCache::new(x),
+        };
+        // client certificate
+        if !config.cert.is_empty() {
+            let passphrase = must!(load_passphrase(
+                &config.key_passphrase,
+                &config.key_passphrase_file
+            ));
+            let (certs, key) = must!(load_cert_key(
+                &config.cert,
+                &config.key,
+                passphrase.as_deref()
+            ));
+            must!(tlsc.set_single_client_cert(certs, key));
+        };
+        // pins, with or without chain validation
+        if !config.pins.is_empty() {
+            let verifier = ClientPinVerify {
+                pins: config.pins.iter().map(|x| parse_pin(x)).collect(),
+                verify_chain: !config.skip_verify,
+            };
+            tlsc.dangerous()
+                .set_certificate_verifier(Arc::new(verifier));
+            if config.skip_verify {
+                return tlsc;
+            }
+        } else if config.skip_verify {
+            // skip verify
             tlsc.dangerous()
                 .set_certificate_verifier(Arc::new(ClientSkipVerify));
             return tlsc;
@@ -169,20 +461,177 @@ pub mod enable_tls {
     }
 
     // TLS Server
-    #[derive(Debug, Serialize, Deserialize)]
+    #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
     pub struct TLSServerConfig {
+        /// default certificate, could be omitted if certs are provided
+        #[serde(default)]
         pub cert: String,
 
+        #[serde(default)]
         pub key: String,
 
+        /// passphrase of an encrypted pkcs8 key
+        #[serde(default)]
+        pub key_passphrase: String,
+
+        /// or read the passphrase from a file
+        #[serde(default)]
+        pub key_passphrase_file: String,
+
+        /// certificates selected by sni
+        #[serde(default)]
+        pub certs: Vec<TLSCertConfig>,
+
+        /// do not fall back to the default certificate
+        /// if the sni matches none of certs
+        #[serde(default)]
+        pub reject_unknown_sni: bool,
+
+        /// issue certificates from a local ca in this directory
+        /// if cert == key, instead of self-signing
+        #[serde(default)]
+        pub ca_dir: String,
+
         #[serde(default)]
         pub alpns: Vec<String>,
 
         #[serde(default)]
         pub versions: Vec<String>,
 
+        #[serde(default)]
+        pub ciphersuites: Vec<String>,
+
+        /// issue stateless session tickets
+        #[serde(default)]
+        pub session_tickets: bool,
+
+        /// sessions kept for resumption, 0 = disable
+        #[serde(default = "def_server_session_cache")]
+        pub session_cache: usize,
+
+        /// wait(default), accept, reject. when to release early data
+        /// to the remote, quic only
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        pub early_data: String,
+
         #[serde(default)]
         pub ocsp: String,
+
+        /// ca file to verify client certificates, enables mutual tls
+        #[serde(default)]
+        pub client_ca: String,
+
+        /// required, optional
+        #[serde(default = "def_client_auth_str")]
+        pub client_auth: String,
+
+        /// allowed clients, either the common name or the full subject
+        /// of the certificate. empty = any client trusted by the ca
+        #[serde(default)]
+        pub client_subjects: Vec<String>,
+
+        /// seconds between checking if the files are modified,
+        /// 0 = only reload on SIGHUP
+        #[serde(default)]
+        pub reload_interval: u64,
+
+        /// write tls secrets to this file, for debugging.
+        /// SSLKEYLOGFILE is used if empty
+        #[serde(default)]
+        pub keylog: String,
+
+        /// forward non-tls connections, or unknown sni and alpn
+        /// to this address, e.g. a web server
+        #[serde(default)]
+        pub fallback: String,
+
+        /// hand the record layer over to the kernel after the handshake,
+        /// so that plain endpoints could splice. linux, tlsv1.3 only
+        #[serde(default)]
+        pub ktls: bool,
+    }
+
+    impl Default for TLSServerConfig {
+        fn default() -> Self {
+            TLSServerConfig {
+                cert: String::new(),
+                key: String::new(),
+                key_passphrase: String::new(),
+                key_passphrase_file: String::new(),
+                certs: Vec::new(),
+                reject_unknown_sni: false,
+                ca_dir: String::new(),
+                alpns: Vec::new(),
+                versions: Vec::new(),
+                ciphersuites: Vec::new(),
+                session_tickets: false,
+                session_cache: def_server_session_cache(),
+                early_data: String::new(),
+                ocsp: String::new(),
+                client_ca: String::new(),
+                client_auth: def_client_auth_str(),
+                client_subjects: Vec::new(),
+                reload_interval: 0,
+                keylog: String::new(),
+                fallback: String::new(),
+                ktls: false,
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+    pub struct TLSCertConfig {
+        /// exact names, or wildcards like *.example.com
+        pub server_names: Vec<String>,
+
+        pub cert: String,
+
+        pub key: String,
+
+        /// passphrase of an encrypted pkcs8 key
+        #[serde(default)]
+        pub key_passphrase: String,
+
+        /// or read the passphrase from a file
+        #[serde(default)]
+        pub key_passphrase_file: String,
+
+        #[serde(default)]
+        pub ocsp: String,
+    }
+
+    // Select the certificate by sni, fall back to the default one.
+    struct SniResolver {
+        certs: HashMap<String, CertifiedKey>,
+        default: Option<CertifiedKey>,
+        reject_unknown_sni: bool,
+    }
+
+    impl SniResolver {
+        fn lookup(&self, name: &str) -> Option<&CertifiedKey> {
+            let name = name.to_ascii_lowercase();
+            self.certs.get(&name).or_else(|| {
+                let (_, parent) = name.split_once('.')?;
+                self.certs.get(&format!("*.{}", parent))
+            })
+        }
+    }
+
+    impl ResolvesServerCert for SniResolver {
+        fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+            let name = match client_hello.server_name() {
+                Some(name) => name.into(),
+                None => return self.default.clone(),
+            };
+            match self.lookup(name) {
+                Some(x) => Some(x.clone()),
+                None if self.reject_unknown_sni => {
+                    warn!("tls: reject unknown sni: {}", name);
+                    None
+                }
+                None => self.default.clone(),
+            }
+        }
     }
 
     use crate::utils::MaybeQuic;
@@ -190,9 +639,92 @@ pub mod enable_tls {
     impl TLSServerConfig {
         pub fn to_tls(&self) -> ServerConfig { make_server_config(self) }
 
-        pub fn apply_to_lis<L: AsyncAccept>(&self, lis: L) -> impl AsyncAccept {
+        // None if client certificates are not verified
+        pub fn client_auth(&self) -> Option<ClientAuth> {
+            if self.client_ca.is_empty() {
+                if !self.client_subjects.is_empty() {
+                    panic!("client_subjects requires client_ca");
+                }
+                return None;
+            }
+            Some(ClientAuth::new(self.client_subjects.clone()))
+        }
+
+        pub fn apply_to_lis<L: AsyncAccept>(&self, lis: L) -> tls::Acceptor<L> {
+            // rustls only accepts early data with quic
+            if !self.early_data.is_empty() {
+                panic!("early_data only applies to quic");
+            }
             let config = make_server_config(self);
-            tls::Acceptor::new(lis, config)
+            let lis = tls::Acceptor::new(
+                lis,
+                config,
+                self.client_auth(),
+                self.fallback(),
+            );
+            tokio::spawn(reload(self.clone(), lis.server_config()));
+            lis
+        }
+
+        fn fallback(&self) -> Option<tls::Fallback> {
+            if self.fallback.is_empty() {
+                return None;
+            }
+            let addr = must!(parse_socket_addr(&self.fallback, true));
+            let conn = plain::Connector::new(addr, dns::resolver(None));
+            Some(tls::Fallback::new(
+                conn,
+                self.known_names(),
+                self.default_cert().is_some(),
+                self.alpns.iter().map(|x| x.as_bytes().to_vec()).collect(),
+            ))
+        }
+
+        // names of $certs and the generated default certificate,
+        // None if only a default certificate loaded from file is known
+        pub(super) fn known_names(&self) -> Option<HashSet<String>> {
+            let generated = self.default_cert().filter(|c| c.cert == c.key);
+            let names: HashSet<String> = self
+                .certs
+                .iter()
+                .chain(generated.iter())
+                .flat_map(|c| &c.server_names)
+                .map(|x| x.to_ascii_lowercase())
+                .collect();
+            if names.is_empty() {
+                None
+            } else {
+                Some(names)
+            }
+        }
+
+        // the default certificate, named after $cert
+        fn default_cert(&self) -> Option<TLSCertConfig> {
+            if self.cert.is_empty() && self.key.is_empty() {
+                return None;
+            }
+            Some(TLSCertConfig {
+                server_names: vec![self.cert.clone()],
+                cert: self.cert.clone(),
+                key: self.key.clone(),
+                key_passphrase: self.key_passphrase.clone(),
+                key_passphrase_file: self.key_passphrase_file.clone(),
+                ocsp: self.ocsp.clone(),
+            })
+        }
+
+        // cert, key, ocsp and client ca files,
+        // generated certificates are excluded
+        fn files(&self) -> Vec<String> {
+            let mut files = Vec::new();
+            let certs =
+                self.default_cert().into_iter().chain(self.certs.clone());
+            for c in certs.filter(|c| c.cert != c.key) {
+                files.extend([c.cert, c.key, c.ocsp, c.key_passphrase_file]);
+            }
+            files.push(self.client_ca.clone());
+            files.retain(|x| !x.is_empty());
+            files
         }
 
         pub fn apply_to_lis_ext<L: AsyncAccept>(
@@ -207,8 +739,114 @@ pub mod enable_tls {
         }
     }
 
+    // Rebuild the server config on SIGHUP, or once the files are modified.
+    // Sessions established before are not affected, and a broken
+    // certificate is not loaded. Generated certificates are kept as is.
+    // Quic listeners are not reloaded, see new_quic_raw_lis.
+    async fn reload(
+        config: TLSServerConfig,
+        shared: Arc<RwLock<Arc<ServerConfig>>>,
+    ) {
+        let files = config.files();
+        if files.is_empty() {
+            return;
+        }
+        let modified = || -> Vec<Option<SystemTime>> {
+            files
+                .iter()
+                .map(|x| fs::metadata(x).and_then(|m| m.modified()).ok())
+                .collect()
+        };
+        let mut last_modified = modified();
+        #[cfg(unix)]
+        let mut hangup = must!(
+            tokio::signal::unix::signal(
+                tokio::signal::unix::SignalKind::hangup()
+            ),
+            "listen for SIGHUP"
+        );
+        loop {
+            let tick = async {
+                match config.reload_interval {
+                    0 => future::pending().await,
+                    x => tokio::time::sleep(Duration::from_secs(x)).await,
+                }
+            };
+            #[cfg(unix)]
+            let forced = {
+                let hup = hangup.recv();
+                futures::pin_mut!(tick, hup);
+                matches!(future::select(tick, hup).await, Either::Right(_))
+            };
+            #[cfg(not(unix))]
+            let forced = {
+                tick.await;
+                false
+            };
+            // the cert and key may not be written at the same time,
+            // a broken pair is retried once modified again
+            let now = modified();
+            if !forced && now == last_modified {
+                continue;
+            }
+            last_modified = now;
+            match try_make_server_config(&config) {
+                Ok(x) => {
+                    *shared.write().unwrap() = Arc::new(x);
+                    info!("tls: reloaded {}", files.join(", "));
+                }
+                Err(e) => warn!("tls: keep the old certificate, {}", e),
+            }
+        }
+    }
+
     fn make_server_config(config: &TLSServerConfig) -> ServerConfig {
-        let mut tlsc = ServerConfig::new(NoClientAuth::new());
+        must!(try_make_server_config(config))
+    }
+
+    fn load_err<E: std::error::Error>(
+        path: &str,
+    ) -> impl FnOnce(E) -> io::Error + '_ {
+        move |e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("load {}: {}", path, e),
+            )
+        }
+    }
+
+    fn try_make_server_config(
+        config: &TLSServerConfig,
+    ) -> io::Result<ServerConfig> {
+        let verifier = if config.client_ca.is_empty() {
+            NoClientAuth::new()
+        } else {
+            let ca = &config.client_ca;
+            let mut roots = RootCertStore::empty();
+            let certs = utils::load_certs(ca).map_err(load_err(ca))?;
+            for cert in certs.iter() {
+                roots.add(cert).map_err(load_err(ca))?;
+            }
+            match config.client_auth.as_str() {
+                "required" => AllowAnyAuthenticatedClient::new(roots),
+                "optional" => {
+                    AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+                }
+                x => panic!("unknown client auth: {}", x),
+            }
+        };
+        let mut tlsc = ServerConfig::new(verifier);
+        tlsc.key_log = make_key_log(&config.keylog)?;
+        // also collect the secrets to hand over
+        if config.ktls {
+            #[cfg(not(target_os = "linux"))]
+            panic!("ktls is only supported on linux");
+            #[cfg(target_os = "linux")]
+            {
+                let key_log = tlsc.key_log.clone();
+                tlsc.key_log = Arc::new(tls::OffloadKeyLog(key_log));
+            }
+        }
         // if not specified, use the constructor's default value
         if !config.alpns.is_empty() {
             tlsc.alpn_protocols =
@@ -226,31 +864,212 @@ pub mod enable_tls {
                 })
                 .collect();
         };
-        let (certs, key) = if config.cert == config.key {
-            must!(utils::generate_cert_key(&config.cert))
-        } else {
-            let certs =
-                must!(utils::load_certs(&config.cert), "load {}", &config.cert);
-            let mut keys =
-                must!(utils::load_keys(&config.key), "load {}", &config.key);
-            (certs, keys.remove(0))
+        // the kernel only takes over tlsv1.3 here
+        if config.ktls {
+            if !tlsc.versions.contains(&ProtocolVersion::TLSv1_3) {
+                panic!("ktls requires tlsv1.3");
+            }
+            tlsc.versions = vec![ProtocolVersion::TLSv1_3];
+        }
+        // the same as alpns
+        if !config.ciphersuites.is_empty() {
+            tlsc.ciphersuites = parse_ciphersuites(&config.ciphersuites);
         };
-        let mut ocsp = vec![0u8];
-        if !config.ocsp.is_empty() {
-            ocsp.reserve(utils::OCSP_BUF_SIZE);
-            let mut r = BufReader::new(must!(
-                fs::File::open(&config.ocsp),
-                "open {}",
-                &config.ocsp
-            ));
-            must!(r.read_to_end(&mut ocsp), "load {}", &config.ocsp);
+        // session resumption
+        tlsc.session_storage = match config.session_cache {
+            0 => Arc::new(NoServerSessionStorage {}),
+            x => ServerSessionMemoryCache::new(x),
+        };
+        if config.session_tickets {
+            tlsc.ticketer = Ticketer::new();
+        };
+        // the default certificate
+        let default = match config.default_cert() {
+            Some(c) => Some(make_certified_key(&c, &config.ca_dir)?),
+            None if config.certs.is_empty() => {
+                panic!("no certificate provided")
+            }
+            None => None,
+        };
+        // certificates selected by sni
+        let mut certs = HashMap::new();
+        for c in config.certs.iter() {
+            if c.server_names.is_empty() {
+                panic!("no server names for {}", &c.cert);
+            }
+            let ck = make_certified_key(c, &config.ca_dir)?;
+            for name in c.server_names.iter() {
+                certs.insert(name.to_ascii_lowercase(), ck.clone());
+            }
         }
-        must!(tlsc.set_single_cert_with_ocsp_and_sct(
+        tlsc.cert_resolver = Arc::new(SniResolver {
             certs,
-            key,
-            ocsp,
-            Vec::new()
-        ));
-        tlsc
+            default,
+            reject_unknown_sni: config.reject_unknown_sni,
+        });
+        Ok(tlsc)
+    }
+
+    lazy_static! {
+        // generated certificates, kept across reloads
+        // so that the pins and roots of clients stay valid
+        static ref GENERATED: Mutex<HashMap<String, CertifiedKey>> =
+            Mutex::new(HashMap::new());
+    }
+
+    fn make_certified_key(
+        c: &TLSCertConfig,
+        ca_dir: &str,
+    ) -> io::Result<CertifiedKey> {
+        if c.cert != c.key {
+            return build_certified_key(c, ca_dir);
+        }
+        let id = format!("{}|{}|{}", &c.cert, ca_dir, c.server_names.join(","));
+        if let Some(ck) = GENERATED.lock().unwrap().get(&id) {
+            return Ok(ck.clone());
+        }
+        let ck = build_certified_key(c, ca_dir)?;
+        GENERATED.lock().unwrap().insert(id, ck.clone());
+        Ok(ck)
+    }
+
+    // generated if cert == key, self-signed or issued by the local ca
+    fn build_certified_key(
+        c: &TLSCertConfig,
+        ca_dir: &str,
+    ) -> io::Result<CertifiedKey> {
+        let (cert, key, ocsp) = (&c.cert, &c.key, &c.ocsp);
+        let (certs, sk) = if cert != key {
+            let passphrase =
+                load_passphrase(&c.key_passphrase, &c.key_passphrase_file)?;
+            load_cert_key(cert, key, passphrase.as_deref())?
+        } else if !ca_dir.is_empty() {
+            utils::load_or_create_ca(ca_dir)
+                .and_then(|ca| utils::issue_cert_key(&ca, &c.server_names))
+                .map_err(load_err(ca_dir))?
+        } else {
+            utils::generate_cert_key(cert).map_err(load_err(cert))?
+        };
+        let sk = sign::any_supported_type(&sk)
+            .map_err(|_| CertError::LoadPrivateKey)
+            .map_err(load_err(key))?;
+        if let Some(leaf) = certs.first() {
+            utils::check_key_pair(leaf, sk.as_ref()).map_err(load_err(key))?;
+        }
+        let mut ck = CertifiedKey::new(certs, Arc::new(sk));
+        ck.cross_check_end_entity_cert(None)
+            .map_err(load_err(cert))?;
+        if !ocsp.is_empty() {
+            let mut buf = Vec::with_capacity(utils::OCSP_BUF_SIZE);
+            let mut r =
+                BufReader::new(fs::File::open(ocsp).map_err(load_err(ocsp))?);
+            r.read_to_end(&mut buf).map_err(load_err(ocsp))?;
+            ck.ocsp = Some(buf);
+        }
+        Ok(ck)
+    }
+
+    fn load_cert_key(
+        cert: &str,
+        key: &str,
+        passphrase: Option<&[u8]>,
+    ) -> io::Result<(Vec<rustls::Certificate>, rustls::PrivateKey)> {
+        let certs = utils::load_certs(cert).map_err(load_err(cert))?;
+        let mut keys =
+            utils::load_keys(key, passphrase).map_err(load_err(key))?;
+        Ok((certs, keys.remove(0)))
+    }
+
+    // the file takes precedence, without the trailing newline
+    fn load_passphrase(
+        passphrase: &str,
+        file: &str,
+    ) -> io::Result<Option<Vec<u8>>> {
+        if !file.is_empty() {
+            let mut buf = fs::read(file).map_err(load_err(file))?;
+            while matches!(buf.last(), Some(b'\n' | b'\r')) {
+                buf.pop();
+            }
+            return Ok(Some(buf));
+        }
+        if passphrase.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(passphrase.as_bytes().to_vec()))
+    }
+}
+
+#[cfg(all(test, feature = "tls"))]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> TLSConfig { serde_json::from_str(s).unwrap() }
+
+    #[test]
+    fn tell_client_from_server() {
+        assert!(matches!(parse("null"), TLSConfig::None));
+        // skip_verify could be omitted
+        let client = parse(r#"{"sni": "x", "roots": "firefox"}"#);
+        assert!(matches!(client, TLSConfig::Client(ref x) if !x.skip_verify));
+        let client = parse(r#"{"alpns": ["h2"]}"#);
+        assert!(matches!(client, TLSConfig::Client(_)));
+        // a client presenting a certificate
+        let client = parse(r#"{"cert": "c", "key": "k", "sni": "x"}"#);
+        assert!(matches!(client, TLSConfig::Client(_)));
+
+        let server = parse(r#"{"cert": "c", "key": "k"}"#);
+        assert!(matches!(server, TLSConfig::Server(_)));
+        let server = parse(
+            r#"{"certs": [{"server_names": ["x"], "cert": "c", "key": "k"}]}"#,
+        );
+        assert!(matches!(server, TLSConfig::Server(_)));
+
+        // unknown or legacy fields are ignored
+        let server = parse(r#"{"cert": "c", "key": "k", "legacy": "x"}"#);
+        assert!(matches!(server, TLSConfig::Server(_)));
+    }
+
+    #[test]
+    fn tell_client_by_role() {
+        // a client presenting a certificate, without any client field
+        let client = parse(r#"{"cert": "c", "key": "k", "role": "client"}"#);
+        assert!(matches!(client, TLSConfig::Client(ref x) if x.cert == "c"));
+        let server = parse(r#"{"cert": "c", "key": "k", "role": "server"}"#);
+        assert!(matches!(server, TLSConfig::Server(_)));
+        // the role wins over the fields
+        let server = parse(r#"{"cert": "c", "sni": "x", "role": "server"}"#);
+        assert!(matches!(server, TLSConfig::Server(_)));
+
+        let invalid = r#"{"cert": "c", "key": "k", "role": "peer"}"#;
+        assert!(serde_json::from_str::<TLSConfig>(invalid).is_err());
+        let invalid = r#"{"cert": "c", "key": "k", "role": 1}"#;
+        assert!(serde_json::from_str::<TLSConfig>(invalid).is_err());
+    }
+
+    #[test]
+    fn fallback_known_names() {
+        let server = |s: &str| match parse(s) {
+            TLSConfig::Server(x) => x,
+            _ => unreachable!(),
+        };
+        // regardless of reject_unknown_sni
+        let names = server(
+            r#"{
+                "cert": "a.pem", "key": "a.key",
+                "certs": [{"server_names": ["X.com"], "cert": "c", "key": "k"}]
+            }"#,
+        )
+        .known_names()
+        .unwrap();
+        assert_eq!(names.into_iter().collect::<Vec<_>>(), ["x.com"]);
+        // the generated certificate is named after $cert
+        let names = server(r#"{"cert": "y.com", "key": "y.com"}"#)
+            .known_names()
+            .unwrap();
+        assert_eq!(names.into_iter().collect::<Vec<_>>(), ["y.com"]);
+        // any name gets the default certificate
+        assert!(server(r#"{"cert": "a.pem", "key": "a.key"}"#)
+            .known_names()
+            .is_none());
     }
 }
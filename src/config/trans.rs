@@ -1,14 +1,16 @@
 use std::sync::Arc;
 use std::fmt::{Display, Formatter};
 use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
 
 use super::WithTransport;
 use crate::transport::{AsyncConnect, AsyncAccept};
 
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema)]
 #[serde(tag = "proto", rename_all = "lowercase")]
 pub enum TransportConfig {
+    #[default]
     Plain,
     #[cfg(feature = "ws")]
     WS(WebSocketConfig),
@@ -18,10 +20,6 @@ pub enum TransportConfig {
     QUIC(QuicConfig),
 }
 
-impl Default for TransportConfig {
-    fn default() -> Self { Self::Plain }
-}
-
 impl Display for TransportConfig {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         use TransportConfig::*;
@@ -39,13 +37,13 @@ impl Display for TransportConfig {
 
 // ===== Details =====
 #[cfg(feature = "ws")]
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct WebSocketConfig {
     pub path: String,
 }
 
 #[cfg(feature = "h2c")]
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct HTTP2Config {
     pub path: String,
 
@@ -56,7 +54,7 @@ pub struct HTTP2Config {
 }
 
 #[cfg(feature = "quic")]
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct QuicConfig {
     #[serde(default)]
     pub mux: usize,
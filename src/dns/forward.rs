@@ -0,0 +1,559 @@
+use std::io::Result;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::task::{Poll, Context, Waker};
+
+use log::{debug, warn};
+use async_trait::async_trait;
+use futures::{ready, StreamExt};
+use futures::channel::mpsc::{self, UnboundedSender, UnboundedReceiver};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use trust_dns_resolver::error::ResolveErrorKind;
+use trust_dns_resolver::proto::op::{Edns, Message, MessageType, ResponseCode};
+use trust_dns_resolver::proto::op::Query;
+use trust_dns_resolver::proto::rr::{DNSClass, RData, Record, RecordType};
+use trust_dns_resolver::proto::xfer::DnsRequestOptions;
+
+use super::Resolver;
+use crate::utils::CommonAddr;
+use crate::transport::{AsyncConnect, Transport};
+
+// max size of a response over udp without edns
+const MAX_UDP_PAYLOAD: u16 = 512;
+
+// ttl(s) of the answers from static hosts
+const HOSTS_TTL: u32 = 60;
+
+// queries of a stream answered at the same time,
+// later ones wait until the responses are read
+const MAX_PENDING_QUERIES: usize = 64;
+
+// Answer dns queries with the resolver, instead of
+// connecting to a real remote.
+pub struct Forwarder {
+    addr: CommonAddr,
+    resolver: Resolver,
+    datagram: bool,
+}
+
+impl Forwarder {
+    // datagram: one query per read/write, used by udp
+    // otherwise: length-prefixed queries, see RFC 1035 4.2.2
+    pub fn new(addr: CommonAddr, resolver: Resolver, datagram: bool) -> Self {
+        Forwarder {
+            addr,
+            resolver,
+            datagram,
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncConnect for Forwarder {
+    const TRANS: Transport = Transport::DNS;
+
+    const SCHEME: &'static str = "dns";
+
+    type IO = DnsStream;
+
+    #[inline]
+    fn addr(&self) -> &CommonAddr { &self.addr }
+
+    #[inline]
+    fn resolver(&self) -> &Resolver { &self.resolver }
+
+    fn clear_reuse(&self) {}
+
+    async fn connect(&self) -> Result<Self::IO> {
+        Ok(DnsStream::new(self.resolver.clone(), self.datagram))
+    }
+}
+
+pub struct DnsStream {
+    resolver: Resolver,
+    datagram: bool,
+    // queries not spawned yet, the last one could be incomplete
+    query: Vec<u8>,
+    // pending response
+    response: Vec<u8>,
+    // spawned queries whose responses are not read
+    pending: usize,
+    writer: Option<Waker>,
+    // dropped after shutdown, then reads EOF
+    // once all queries are answered, or dropped(None)
+    send: Option<UnboundedSender<Option<Vec<u8>>>>,
+    recv: UnboundedReceiver<Option<Vec<u8>>>,
+}
+
+impl DnsStream {
+    fn new(resolver: Resolver, datagram: bool) -> Self {
+        let (send, recv) = mpsc::unbounded();
+        DnsStream {
+            resolver,
+            datagram,
+            query: Vec::new(),
+            response: Vec::new(),
+            pending: 0,
+            writer: None,
+            send: Some(send),
+            recv,
+        }
+    }
+
+    fn spawn_query(&mut self, query: Vec<u8>) {
+        let send = match &self.send {
+            Some(send) => send.clone(),
+            None => return,
+        };
+        self.pending += 1;
+        let resolver = self.resolver.clone();
+        let datagram = self.datagram;
+        tokio::spawn(async move {
+            let mut response = answer(&resolver, &query, datagram).await;
+            if let (Some(response), false) = (&mut response, datagram) {
+                let len = (response.len() as u16).to_be_bytes();
+                response.splice(0..0, len);
+            }
+            let _ = send.unbounded_send(response);
+        });
+    }
+
+    // length of the next complete query, with the prefix
+    fn next_query(&self) -> Option<usize> {
+        if self.query.len() < 2 {
+            return None;
+        }
+        let len = u16::from_be_bytes([self.query[0], self.query[1]]);
+        let len = len as usize + 2;
+        if self.query.len() < len {
+            return None;
+        }
+        Some(len)
+    }
+
+    fn spawn_queued(&mut self) {
+        while self.pending < MAX_PENDING_QUERIES {
+            let len = match self.next_query() {
+                Some(len) => len,
+                None => break,
+            };
+            let query: Vec<u8> = self.query.drain(..len).skip(2).collect();
+            self.spawn_query(query);
+        }
+    }
+
+    // all complete queries are spawned
+    fn poll_queued(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.next_query().is_some() {
+            self.writer = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        Poll::Ready(())
+    }
+
+    fn wake_writer(&mut self) {
+        if let Some(waker) = self.writer.take() {
+            waker.wake();
+        }
+    }
+}
+
+async fn answer(
+    resolver: &Resolver,
+    query: &[u8],
+    datagram: bool,
+) -> Option<Vec<u8>> {
+    let request = match Message::from_vec(query) {
+        Ok(request) => request,
+        Err(e) => {
+            warn!("dns forward: drop invalid query, {}", e);
+            return None;
+        }
+    };
+
+    let mut response = Message::new();
+    response
+        .set_id(request.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request.op_code())
+        .set_recursion_desired(request.recursion_desired())
+        .set_recursion_available(true);
+    if let Some(edns) = request.edns() {
+        let mut edns_resp = Edns::new();
+        edns_resp.set_max_payload(edns.max_payload().max(MAX_UDP_PAYLOAD));
+        response.set_edns(edns_resp);
+    }
+
+    match request.queries().first() {
+        Some(query) => {
+            debug!("dns forward: {} {}", query.name(), query.query_type());
+            response.add_query(query.clone());
+            if let Some(answers) = lookup_hosts(resolver, query) {
+                debug!("dns forward: {} from hosts", query.name());
+                response.add_answers(answers);
+                return finish(&request, response, datagram);
+            }
+            let lookup = resolver.dns.lookup(
+                query.name().clone(),
+                query.query_type(),
+                DnsRequestOptions::default(),
+            );
+            match lookup.await {
+                Ok(lookup) => {
+                    response.add_answers(lookup.record_iter().cloned());
+                }
+                Err(e) => {
+                    let code = match e.kind() {
+                        ResolveErrorKind::NoRecordsFound {
+                            response_code,
+                            ..
+                        } => *response_code,
+                        _ => ResponseCode::ServFail,
+                    };
+                    debug!("dns forward: {}", e);
+                    response.set_response_code(code);
+                }
+            }
+        }
+        None => {
+            response.set_response_code(ResponseCode::FormErr);
+        }
+    }
+
+    finish(&request, response, datagram)
+}
+
+fn finish(
+    request: &Message,
+    mut response: Message,
+    datagram: bool,
+) -> Option<Vec<u8>> {
+    let mut buf = response.to_vec().ok()?;
+    // let the client retry with tcp
+    if datagram {
+        let max_payload = request
+            .edns()
+            .map_or(MAX_UDP_PAYLOAD, |x| x.max_payload().max(MAX_UDP_PAYLOAD));
+        if buf.len() > max_payload as usize {
+            response.take_answers();
+            response.set_truncated(true);
+            buf = response.to_vec().ok()?;
+        }
+    }
+    Some(buf)
+}
+
+// A and AAAA of the static hosts, like Resolver::resolve_all,
+// the name has no address of the other family then
+fn lookup_hosts(resolver: &Resolver, query: &Query) -> Option<Vec<Record>> {
+    let rtype = query.query_type();
+    if query.query_class() != DNSClass::IN
+        || !matches!(rtype, RecordType::A | RecordType::AAAA)
+    {
+        return None;
+    }
+    let name = query.name().to_utf8();
+    let ips = resolver.lookup_hosts(name.trim_end_matches('.'))?;
+    let answers = ips
+        .into_iter()
+        .filter_map(|ip| match (ip, rtype) {
+            (IpAddr::V4(x), RecordType::A) => Some(RData::A(x)),
+            (IpAddr::V6(x), RecordType::AAAA) => Some(RData::AAAA(x)),
+            _ => None,
+        })
+        .map(|x| Record::from_rdata(query.name().clone(), HOSTS_TTL, x))
+        .collect();
+    Some(answers)
+}
+
+impl AsyncRead for DnsStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        while self.response.is_empty() {
+            match ready!(self.recv.poll_next_unpin(cx)) {
+                Some(response) => {
+                    self.pending -= 1;
+                    self.spawn_queued();
+                    self.wake_writer();
+                    // an invalid query is dropped
+                    self.response = response.unwrap_or_default();
+                }
+                // reads 0, EOF
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let to_read = std::cmp::min(buf.remaining(), self.response.len());
+        buf.put_slice(&self.response[..to_read]);
+        self.response.drain(..to_read);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for DnsStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        // woken once a response is read
+        if self.pending >= MAX_PENDING_QUERIES {
+            self.writer = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        if self.datagram {
+            self.spawn_query(buf.to_vec());
+            return Poll::Ready(Ok(buf.len()));
+        }
+        // the rest are spawned by later reads
+        self.query.extend_from_slice(buf);
+        self.spawn_queued();
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    #[inline]
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<()>> {
+        ready!(self.poll_queued(cx));
+        Poll::Ready(Ok(()))
+    }
+
+    #[inline]
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<()>> {
+        ready!(self.poll_queued(cx));
+        self.send = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use futures::FutureExt;
+    use futures::future::poll_fn;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use trust_dns_resolver::proto::op::OpCode;
+    use trust_dns_resolver::proto::rr::Name;
+
+    use crate::config::DnsMode;
+    use crate::dns::mock;
+
+    fn query(id: u16, name: &str, rtype: RecordType, edns: bool) -> Vec<u8> {
+        let mut msg = Message::new();
+        msg.set_id(id)
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(true);
+        if !name.is_empty() {
+            let name = Name::from_ascii(name).unwrap();
+            msg.add_query(Query::query(name, rtype));
+        }
+        if edns {
+            let mut edns = Edns::new();
+            edns.set_max_payload(4096);
+            msg.set_edns(edns);
+        }
+        msg.to_vec().unwrap()
+    }
+
+    fn framed(query: &[u8]) -> Vec<u8> {
+        let mut buf = (query.len() as u16).to_be_bytes().to_vec();
+        buf.extend_from_slice(query);
+        buf
+    }
+
+    async fn read_framed(stream: &mut DnsStream) -> Message {
+        let mut len = [0u8; 2];
+        stream.read_exact(&mut len).await.unwrap();
+        let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut buf).await.unwrap();
+        Message::from_vec(&buf).unwrap()
+    }
+
+    async fn read_datagram(stream: &mut DnsStream) -> Message {
+        let mut buf = vec![0u8; 0x10000];
+        let n = stream.read(&mut buf).await.unwrap();
+        Message::from_vec(&buf[..n]).unwrap()
+    }
+
+    fn ips(msg: &Message) -> Vec<IpAddr> {
+        msg.answers()
+            .iter()
+            .filter_map(|x| match x.rdata() {
+                RData::A(x) => Some(IpAddr::V4(*x)),
+                RData::AAAA(x) => Some(IpAddr::V6(*x)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    async fn upstream() -> Resolver {
+        let mut answers = HashMap::new();
+        answers.insert("a.test".to_string(), vec!["10.0.0.1".parse().unwrap()]);
+        // too many for 512 bytes
+        let many = (1..=40).map(|x| IpAddr::from([10, 0, 1, x])).collect();
+        answers.insert("many.test".to_string(), many);
+        let server = mock::serve(answers).await;
+        let mut hosts = HashMap::new();
+        hosts.insert(
+            "h.test".to_string(),
+            vec!["1.2.3.4".parse().unwrap(), "::1".parse().unwrap()],
+        );
+        mock::with_hosts(DnsMode::default(), server, hosts)
+    }
+
+    #[test]
+    fn stream_split_writes() {
+        runtime().block_on(async {
+            let mut stream = DnsStream::new(upstream().await, false);
+            let first = framed(&query(1, "a.test", RecordType::A, false));
+            let second = framed(&query(2, "h.test", RecordType::A, false));
+            // a prefix split in the middle, then two queries at once
+            let mut buf = first.clone();
+            buf.extend_from_slice(&second);
+            stream.write_all(&buf[..1]).await.unwrap();
+            stream.write_all(&buf[1..first.len() + 3]).await.unwrap();
+            stream.write_all(&buf[first.len() + 3..]).await.unwrap();
+            stream.shutdown().await.unwrap();
+
+            let mut responses = [
+                read_framed(&mut stream).await,
+                read_framed(&mut stream).await,
+            ];
+            responses.sort_by_key(|x| x.id());
+            assert_eq!(
+                ips(&responses[0]),
+                vec!["10.0.0.1".parse::<IpAddr>().unwrap()]
+            );
+            assert_eq!(
+                ips(&responses[1]),
+                vec!["1.2.3.4".parse::<IpAddr>().unwrap()]
+            );
+            // EOF once all are answered
+            assert_eq!(stream.read(&mut [0u8; 1]).await.unwrap(), 0);
+        });
+    }
+
+    #[test]
+    fn truncate_datagram() {
+        runtime().block_on(async {
+            let resolver = upstream().await;
+            let mut stream = DnsStream::new(resolver.clone(), true);
+            stream
+                .write_all(&query(1, "many.test", RecordType::A, false))
+                .await
+                .unwrap();
+            let response = read_datagram(&mut stream).await;
+            assert!(response.truncated());
+            assert!(response.answers().is_empty());
+
+            // fits with edns
+            stream
+                .write_all(&query(2, "many.test", RecordType::A, true))
+                .await
+                .unwrap();
+            let response = read_datagram(&mut stream).await;
+            assert!(!response.truncated());
+            assert_eq!(response.answers().len(), 40);
+            assert_eq!(response.edns().unwrap().max_payload(), 4096);
+
+            // never truncated over a stream
+            let mut stream = DnsStream::new(resolver, false);
+            let buf = framed(&query(3, "many.test", RecordType::A, false));
+            stream.write_all(&buf).await.unwrap();
+            let response = read_framed(&mut stream).await;
+            assert!(!response.truncated());
+            assert_eq!(response.answers().len(), 40);
+        });
+    }
+
+    #[test]
+    fn answer_from_hosts() {
+        runtime().block_on(async {
+            let mut stream = DnsStream::new(upstream().await, true);
+            for (id, rtype, ip) in
+                [(1, RecordType::A, "1.2.3.4"), (2, RecordType::AAAA, "::1")]
+                    .iter()
+            {
+                stream
+                    .write_all(&query(*id, "h.test.", *rtype, false))
+                    .await
+                    .unwrap();
+                let response = read_datagram(&mut stream).await;
+                assert_eq!(response.id(), *id);
+                assert_eq!(response.response_code(), ResponseCode::NoError);
+                assert_eq!(response.queries()[0].query_type(), *rtype);
+                assert_eq!(ips(&response), vec![ip.parse::<IpAddr>().unwrap()]);
+                assert_eq!(response.answers()[0].ttl(), HOSTS_TTL);
+            }
+        });
+    }
+
+    #[test]
+    fn response_codes() {
+        runtime().block_on(async {
+            let mut stream = DnsStream::new(upstream().await, true);
+            for (name, code) in [
+                ("none.test", ResponseCode::NXDomain),
+                (mock::SERVFAIL, ResponseCode::ServFail),
+                ("", ResponseCode::FormErr),
+            ]
+            .iter()
+            {
+                stream
+                    .write_all(&query(1, name, RecordType::A, false))
+                    .await
+                    .unwrap();
+                let response = read_datagram(&mut stream).await;
+                assert_eq!(response.response_code(), *code, "{}", name);
+                assert!(response.answers().is_empty());
+            }
+        });
+    }
+
+    #[test]
+    fn bound_pending_queries() {
+        runtime().block_on(async {
+            let resolver = upstream().await;
+            let q = query(1, "h.test", RecordType::A, false);
+
+            let mut stream = DnsStream::new(resolver.clone(), true);
+            for _ in 0..MAX_PENDING_QUERIES {
+                stream.write_all(&q).await.unwrap();
+            }
+            // waits for a response to be read
+            let mut write =
+                poll_fn(|cx| Pin::new(&mut stream).poll_write(cx, &q));
+            assert!((&mut write).now_or_never().is_none());
+            read_datagram(&mut stream).await;
+            stream.write_all(&q).await.unwrap();
+
+            // the rest of a write are spawned by reads
+            let mut stream = DnsStream::new(resolver, false);
+            let buf = framed(&q).repeat(MAX_PENDING_QUERIES + 2);
+            stream.write_all(&buf).await.unwrap();
+            assert_eq!(stream.pending, MAX_PENDING_QUERIES);
+            for _ in 0..MAX_PENDING_QUERIES + 2 {
+                read_framed(&mut stream).await;
+            }
+            stream.shutdown().await.unwrap();
+            assert_eq!(stream.read(&mut [0u8; 1]).await.unwrap(), 0);
+        });
+    }
+}
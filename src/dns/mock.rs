@@ -0,0 +1,90 @@
+// A fake upstream server and resolvers that only query it.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::collections::HashMap;
+
+use tokio::net::UdpSocket;
+use trust_dns_resolver::proto::op::{Message, MessageType, ResponseCode};
+use trust_dns_resolver::proto::rr::{RData, Record, RecordType};
+
+use super::{Registry, Resolver};
+use crate::config::dns::{DnsMode, DnsServers, DnsCacheConfig};
+
+// the upstream fails to answer it
+pub const SERVFAIL: &str = "servfail.test";
+
+// Answer A and AAAA queries with the addresses of the same family,
+// names without any address are NXDOMAIN.
+pub async fn serve(answers: HashMap<String, Vec<IpAddr>>) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 0x800];
+        loop {
+            let (n, peer) = socket.recv_from(&mut buf).await.unwrap();
+            let query = Message::from_vec(&buf[..n]).unwrap();
+            let response = answer(&query, &answers);
+            let _ = socket.send_to(&response.to_vec().unwrap(), peer).await;
+        }
+    });
+    addr
+}
+
+fn answer(query: &Message, answers: &HashMap<String, Vec<IpAddr>>) -> Message {
+    let mut response = Message::new();
+    response
+        .set_id(query.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(query.op_code())
+        .set_recursion_desired(query.recursion_desired())
+        .set_recursion_available(true)
+        .add_queries(query.queries().to_vec());
+    for q in query.queries() {
+        let name = q.name().to_utf8();
+        let name = name.trim_end_matches('.');
+        if name == SERVFAIL {
+            response.set_response_code(ResponseCode::ServFail);
+            continue;
+        }
+        let ips = match answers.get(name) {
+            Some(x) => x,
+            None => {
+                response.set_response_code(ResponseCode::NXDomain);
+                continue;
+            }
+        };
+        for ip in ips {
+            let rdata = match (q.query_type(), ip) {
+                (RecordType::A, IpAddr::V4(x)) => RData::A(*x),
+                (RecordType::AAAA, IpAddr::V6(x)) => RData::AAAA(*x),
+                _ => continue,
+            };
+            let record = Record::from_rdata(q.name().clone(), 60, rdata);
+            response.add_answer(record);
+        }
+    }
+    response
+}
+
+// built like the configured ones, with the fake server
+pub fn resolver(mode: DnsMode, server: SocketAddr) -> Resolver {
+    with_hosts(mode, server, HashMap::new())
+}
+
+pub fn with_hosts(
+    mode: DnsMode,
+    server: SocketAddr,
+    hosts: HashMap<String, Vec<IpAddr>>,
+) -> Resolver {
+    let node = serde_json::json!({ "addr": server.to_string() });
+    let node = serde_json::from_value(node).unwrap();
+    let registry = Registry {
+        mode,
+        servers: DnsServers::Custom(vec![node]),
+        cache: DnsCacheConfig::default(),
+        hosts: Arc::new(hosts),
+        resolvers: HashMap::new(),
+    };
+    registry.build(None)
+}
@@ -1,64 +1,479 @@
 use std::io::{Result, Error, ErrorKind};
-use std::net::{IpAddr};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use std::collections::HashMap;
 
-use futures::executor;
+use rand::Rng;
 use trust_dns_resolver::TokioAsyncResolver;
-use trust_dns_resolver::config::{
-    ResolverConfig, ResolverOpts, LookupIpStrategy, NameServerConfig,
-};
+use trust_dns_resolver::config::{ResolverConfig, ResolverOpts, NameServerConfig};
+use trust_dns_resolver::config::LookupIpStrategy;
 use lazy_static::lazy_static;
 use log::{debug};
 
-use super::config::dns::{DnsServerNode};
+use super::utils::{must, CommonAddr};
+use super::config::dns::{DnsMode, DnsServers, DnsConfig, DnsCacheConfig};
 
-static mut RESOLVE_STRATEGY: LookupIpStrategy = LookupIpStrategy::Ipv4thenIpv6;
-static mut RESOLVER_CONFIGS: Vec<ResolverConfig> = vec![];
+mod forward;
+pub use forward::Forwarder;
+
+#[cfg(test)]
+pub mod mock;
+
+// Resolvers are shared by endpoints with the same settings.
+// The global one is registered with an empty key.
+struct Registry {
+    mode: DnsMode,
+    servers: DnsServers,
+    cache: DnsCacheConfig,
+    hosts: Arc<HashMap<String, Vec<IpAddr>>>,
+    resolvers: HashMap<String, Resolver>,
+}
 
 lazy_static! {
-    static ref DNS: TokioAsyncResolver = {
-        let resolver_config = { unsafe { RESOLVER_CONFIGS.pop().unwrap() } };
-        TokioAsyncResolver::tokio(
-            resolver_config,
-            ResolverOpts {
-                ip_strategy: unsafe { RESOLVE_STRATEGY },
-                ..Default::default()
-            },
-        )
-    }
-    .unwrap();
+    static ref REGISTRY: Mutex<Option<Registry>> = Mutex::new(None);
 }
 
+static REFRESH_INTERVAL: AtomicU64 = AtomicU64::new(0);
+
 pub fn init_resolver(
-    strategy: LookupIpStrategy,
-    dns_servers: Vec<DnsServerNode>,
+    mode: DnsMode,
+    servers: DnsServers,
+    cache: DnsCacheConfig,
+    hosts: HashMap<String, Vec<IpAddr>>,
 ) {
-    let mut resolver_config = ResolverConfig::new();
-    if dns_servers.is_empty() {
-        resolver_config = ResolverConfig::cloudflare();
-    } else {
-        for dns_server in dns_servers.into_iter() {
-            debug!("load next dns_server");
-            resolver_config.add_name_server(NameServerConfig::from(dns_server));
+    REFRESH_INTERVAL.store(cache.refresh_interval, Ordering::Relaxed);
+    let hosts = hosts
+        .into_iter()
+        .map(|(k, v)| (k.to_lowercase(), v))
+        .collect();
+    let mut registry = Registry {
+        mode,
+        servers,
+        cache,
+        hosts: Arc::new(hosts),
+        resolvers: HashMap::new(),
+    };
+    let global = registry.build(None);
+    registry.resolvers.insert(String::new(), global);
+    *REGISTRY.lock().unwrap() = Some(registry);
+}
+
+// get the resolver of an endpoint, or the global one
+pub fn resolver(config: Option<&DnsConfig>) -> Resolver {
+    let key = config
+        .map(|x| serde_json::to_string(x).unwrap())
+        .unwrap_or_default();
+    let mut registry = REGISTRY.lock().unwrap();
+    let registry = registry.as_mut().expect("resolver is not initialized");
+    if let Some(resolver) = registry.resolvers.get(&key) {
+        return resolver.clone();
+    }
+    debug!("load new resolver: {}", &key);
+    let resolver = registry.build(config);
+    registry.resolvers.insert(key, resolver.clone());
+    resolver
+}
+
+impl Registry {
+    fn build(&self, config: Option<&DnsConfig>) -> Resolver {
+        let mode = config.and_then(|x| x.mode).unwrap_or(self.mode);
+        let servers = config
+            .and_then(|x| x.servers.clone())
+            .unwrap_or_else(|| self.servers.clone());
+        let (resolver_config, resolver_opts) = match servers {
+            DnsServers::Preset(x) => preset_config(&x),
+            DnsServers::Custom(x) if x.is_empty() => preset_config("system"),
+            DnsServers::Custom(x) => {
+                let mut resolver_config = ResolverConfig::new();
+                for dns_server in x.into_iter() {
+                    debug!("load next dns_server");
+                    resolver_config
+                        .add_name_server(NameServerConfig::from(dns_server));
+                }
+                (resolver_config, ResolverOpts::default())
+            }
+        };
+        // the preferred family is picked after both are resolved,
+        // so that happy eyeballs could race them
+        let ip_strategy = match mode {
+            DnsMode::Ipv4Only | DnsMode::Ipv6Only => mode.into(),
+            _ => LookupIpStrategy::Ipv4AndIpv6,
+        };
+        let resolver_opts = ResolverOpts {
+            ip_strategy,
+            ..self.cache.apply_to_opts(resolver_opts)
+        };
+        let dns = must!(
+            TokioAsyncResolver::tokio(resolver_config, resolver_opts),
+            "create resolver"
+        );
+        Resolver {
+            dns: Arc::new(dns),
+            hosts: self.hosts.clone(),
+            mode,
+        }
+    }
+}
+
+fn preset_config(name: &str) -> (ResolverConfig, ResolverOpts) {
+    match name {
+        // read /etc/resolv.conf on unix
+        "system" => must!(
+            trust_dns_resolver::system_conf::read_system_conf(),
+            "load system dns config"
+        ),
+        "cloudflare" => (ResolverConfig::cloudflare(), ResolverOpts::default()),
+        "google" => (ResolverConfig::google(), ResolverOpts::default()),
+        "quad9" => (ResolverConfig::quad9(), ResolverOpts::default()),
+        _ => panic!("unknown dns servers: {}", name),
+    }
+}
+
+#[derive(Clone)]
+pub struct Resolver {
+    dns: Arc<TokioAsyncResolver>,
+    hosts: Arc<HashMap<String, Vec<IpAddr>>>,
+    mode: DnsMode,
+}
+
+impl Resolver {
+    fn lookup_hosts(&self, addr: &str) -> Option<Vec<IpAddr>> {
+        self.hosts
+            .get(&addr.to_lowercase())
+            .filter(|x| !x.is_empty())
+            .cloned()
+    }
+
+    pub async fn resolve(&self, addr: &str) -> Result<IpAddr> {
+        Ok(self.resolve_all(addr).await?.remove(0))
+    }
+
+    // never returns an empty list
+    pub async fn resolve_all(&self, addr: &str) -> Result<Vec<IpAddr>> {
+        if let Some(ips) = self.lookup_hosts(addr) {
+            debug!("resolve {} from hosts", addr);
+            return Ok(ips);
+        }
+        Ok(prefer_family(self.mode, self.lookup_ip(addr).await?))
+    }
+
+    // addresses of both families to race, unless the mode
+    // only allows one. never returns an empty list
+    pub async fn resolve_both(&self, addr: &str) -> Result<Vec<IpAddr>> {
+        if let Some(ips) = self.lookup_hosts(addr) {
+            debug!("resolve {} from hosts", addr);
+            return Ok(ips);
+        }
+        self.lookup_ip(addr).await
+    }
+
+    async fn lookup_ip(&self, addr: &str) -> Result<Vec<IpAddr>> {
+        let ips: Vec<IpAddr> = self
+            .dns
+            .lookup_ip(addr)
+            .await
+            .map_err(|e| Error::new(ErrorKind::Other, e))?
+            .into_iter()
+            .collect();
+        if ips.is_empty() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("no address for {}", addr),
+            ));
+        }
+        Ok(ips)
+    }
+
+    // returns (target, port) in order of preference, see RFC 2782.
+    // records are cached until their ttl expires
+    pub async fn lookup_srv(&self, name: &str) -> Result<Vec<(String, u16)>> {
+        let lookup = self
+            .dns
+            .srv_lookup(name)
+            .await
+            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+        let records: Vec<(u16, u16, String, u16)> = lookup
+            .iter()
+            .map(|x| {
+                let target = x.target().to_utf8();
+                let target = target.trim_end_matches('.').to_string();
+                (x.priority(), x.weight(), target, x.port())
+            })
+            // "." means the service is not available
+            .filter(|x| !x.2.is_empty())
+            .collect();
+        if records.is_empty() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("no service for {}", name),
+            ));
+        }
+        Ok(order_srv(records, &mut rand::thread_rng()))
+    }
+
+    // the preferred address to connect
+    pub async fn resolve_addr(&self, addr: &CommonAddr) -> Result<SocketAddr> {
+        match addr {
+            CommonAddr::SocketAddr(sockaddr) => Ok(*sockaddr),
+            CommonAddr::DomainName(addr, port) => {
+                Ok(SocketAddr::new(self.resolve(addr).await?, *port))
+            }
+            CommonAddr::SrvName(name) => {
+                let mut last_err = None;
+                for (target, port) in self.lookup_srv(name).await? {
+                    match self.resolve(&target).await {
+                        Ok(ip) => return Ok(SocketAddr::new(ip, port)),
+                        Err(e) => last_err = Some(e),
+                    }
+                }
+                Err(last_err.unwrap())
+            }
+            #[cfg(all(unix, feature = "uds"))]
+            CommonAddr::UnixSocketPath(_) => unreachable!(),
+        }
+    }
+
+    // all addresses of a domain name or a service
+    pub async fn resolve_all_addrs(
+        &self,
+        addr: &CommonAddr,
+    ) -> Result<Vec<SocketAddr>> {
+        match addr {
+            CommonAddr::DomainName(addr, port) => Ok(self
+                .resolve_all(addr)
+                .await?
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, *port))
+                .collect()),
+            CommonAddr::SrvName(name) => {
+                let mut addrs = Vec::new();
+                for (target, port) in self.lookup_srv(name).await? {
+                    if let Ok(ips) = self.resolve_all(&target).await {
+                        addrs.extend(
+                            ips.into_iter().map(|ip| SocketAddr::new(ip, port)),
+                        );
+                    }
+                }
+                if addrs.is_empty() {
+                    return Err(Error::new(
+                        ErrorKind::NotFound,
+                        format!("no address for {}", name),
+                    ));
+                }
+                Ok(addrs)
+            }
+            _ => Ok(vec![self.resolve_addr(addr).await?]),
         }
     }
-    unsafe {
-        RESOLVE_STRATEGY = strategy;
-        RESOLVER_CONFIGS.push(resolver_config);
+}
+
+// only the preferred family if any, e.g. ipv4_then_ipv6
+fn prefer_family(mode: DnsMode, ips: Vec<IpAddr>) -> Vec<IpAddr> {
+    let preferred: fn(&IpAddr) -> bool = match mode {
+        DnsMode::Ipv4ThenIpv6 => IpAddr::is_ipv4,
+        DnsMode::Ipv6ThenIpv4 => IpAddr::is_ipv6,
+        _ => return ips,
     };
-    lazy_static::initialize(&DNS);
+    if ips.iter().any(preferred) {
+        ips.into_iter().filter(preferred).collect()
+    } else {
+        ips
+    }
 }
 
-pub fn resolve_sync(addr: &str) -> Result<IpAddr> {
-    executor::block_on(resolve_async(addr))
+// RFC 2782
+// lower priority first, then weighted random selection
+// within the same priority
+fn order_srv<R: Rng>(
+    mut records: Vec<(u16, u16, String, u16)>,
+    rng: &mut R,
+) -> Vec<(String, u16)> {
+    records.sort_by_key(|x| x.0);
+    let mut ordered = Vec::with_capacity(records.len());
+    while !records.is_empty() {
+        // take the group with the lowest priority
+        let priority = records[0].0;
+        let n = records.iter().take_while(|x| x.0 == priority).count();
+        let mut group: Vec<_> = records.drain(..n).collect();
+        // zero weights first
+        group.sort_by_key(|x| x.1);
+        while !group.is_empty() {
+            let total: u32 = group.iter().map(|x| x.1 as u32).sum();
+            let point = rng.gen_range(0..=total);
+            let mut sum = 0;
+            let i = group
+                .iter()
+                .position(|x| {
+                    sum += x.1 as u32;
+                    sum >= point
+                })
+                .unwrap();
+            let (_, _, target, port) = group.remove(i);
+            ordered.push((target, port));
+        }
+    }
+    ordered
 }
 
-pub async fn resolve_async(addr: &str) -> Result<IpAddr> {
-    let res = DNS
-        .lookup_ip(addr)
-        .await
-        .map_err(|e| Error::new(ErrorKind::Other, e))?
-        .into_iter()
-        .next()
-        .unwrap();
-    Ok(res)
+// Watch the addresses of a domain name or a service, which are
+// used by a long-lived(mux) connection.
+pub struct Watcher {
+    resolver: Resolver,
+    state: Mutex<(Vec<SocketAddr>, Instant)>,
+}
+
+impl Watcher {
+    pub fn new(resolver: Resolver) -> Self {
+        Watcher {
+            resolver,
+            state: Mutex::new((Vec::new(), Instant::now())),
+        }
+    }
+
+    // record the addresses when a new connection is established
+    pub async fn reset(&self, addr: &CommonAddr) {
+        if is_name(addr) {
+            let mut addrs = self
+                .resolver
+                .resolve_all_addrs(addr)
+                .await
+                .unwrap_or_default();
+            addrs.sort();
+            *self.state.lock().unwrap() = (addrs, Instant::now());
+        }
+    }
+
+    // re-resolve at most once per refresh interval,
+    // returns true if the answer has changed
+    pub async fn changed(&self, addr: &CommonAddr) -> bool {
+        let interval = REFRESH_INTERVAL.load(Ordering::Relaxed);
+        if interval == 0 || !is_name(addr) {
+            return false;
+        }
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.1.elapsed() < Duration::from_secs(interval) {
+                return false;
+            }
+            state.1 = Instant::now();
+        }
+        let mut addrs = match self.resolver.resolve_all_addrs(addr).await {
+            Ok(addrs) => addrs,
+            // keep using the old connection
+            Err(_) => return false,
+        };
+        addrs.sort();
+        let mut state = self.state.lock().unwrap();
+        if state.0 == addrs {
+            return false;
+        }
+        debug!("dns answer of {} changed: {:?}", addr, &addrs);
+        state.0 = addrs;
+        true
+    }
+}
+
+fn is_name(addr: &CommonAddr) -> bool {
+    matches!(addr, CommonAddr::DomainName(..) | CommonAddr::SrvName(_))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    fn record(
+        priority: u16,
+        weight: u16,
+        target: &str,
+    ) -> (u16, u16, String, u16) {
+        (priority, weight, String::from(target), 443)
+    }
+
+    fn targets(ordered: Vec<(String, u16)>) -> Vec<String> {
+        ordered.into_iter().map(|x| x.0).collect()
+    }
+
+    #[test]
+    fn srv_priority_order() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let records = vec![
+                record(20, 10, "c"),
+                record(10, 0, "a"),
+                record(30, 0, "d"),
+                record(10, 0, "b"),
+            ];
+            let ordered = targets(order_srv(records, &mut rng));
+            assert_eq!(ordered.len(), 4);
+            assert!(ordered[..2].contains(&String::from("a")));
+            assert!(ordered[..2].contains(&String::from("b")));
+            assert_eq!(ordered[2..], ["c", "d"]);
+        }
+    }
+
+    #[test]
+    fn srv_weight_order() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut first = HashMap::new();
+        for _ in 0..10000 {
+            let records = vec![
+                record(10, 0, "zero"),
+                record(10, 10, "light"),
+                record(10, 30, "heavy"),
+            ];
+            let ordered = targets(order_srv(records, &mut rng));
+            assert_eq!(ordered.len(), 3);
+            *first.entry(ordered[0].clone()).or_insert(0) += 1;
+        }
+        // a zero weight is selected only if the point is 0,
+        // about 1/41 of all
+        let zero = first.get("zero").copied().unwrap_or(0);
+        let light = first["light"];
+        let heavy = first["heavy"];
+        assert!(zero < 500, "{:?}", &first);
+        assert!((2000..3000).contains(&light), "{:?}", &first);
+        assert!((7000..8000).contains(&heavy), "{:?}", &first);
+    }
+
+    #[test]
+    fn resolve_families() {
+        let ips = |s: &[&str]| -> Vec<IpAddr> {
+            s.iter().map(|x| x.parse().unwrap()).collect()
+        };
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let mut answers = HashMap::new();
+            answers.insert(String::from("dual.test"), ips(&["1.1.1.1", "::1"]));
+            answers.insert(String::from("v6.test"), ips(&["::2"]));
+            let server = mock::serve(answers).await;
+
+            // ipv4 is preferred, but both are raced
+            let resolver = mock::resolver(DnsMode::Ipv4ThenIpv6, server);
+            let all = resolver.resolve_all("dual.test").await.unwrap();
+            assert_eq!(all, ips(&["1.1.1.1"]));
+            let mut both = resolver.resolve_both("dual.test").await.unwrap();
+            both.sort();
+            assert_eq!(both, ips(&["1.1.1.1", "::1"]));
+            // the other family if the preferred one is missing
+            let all = resolver.resolve_all("v6.test").await.unwrap();
+            assert_eq!(all, ips(&["::2"]));
+
+            let resolver = mock::resolver(DnsMode::Ipv6ThenIpv4, server);
+            let all = resolver.resolve_all("dual.test").await.unwrap();
+            assert_eq!(all, ips(&["::1"]));
+
+            // only one family is allowed
+            let resolver = mock::resolver(DnsMode::Ipv4Only, server);
+            let both = resolver.resolve_both("dual.test").await.unwrap();
+            assert_eq!(both, ips(&["1.1.1.1"]));
+            assert!(resolver.resolve_both("missing.test").await.is_err());
+        });
+    }
 }
@@ -7,6 +7,10 @@ pub type Result<T> = std::result::Result<T, CertError>;
 pub enum CertError {
     LoadCertificate,
     LoadPrivateKey,
+    KeyMismatch,
+    MissingPassphrase,
+    DecryptPrivateKey,
+    MissingCaKey,
     GenCertKey(rcgen::RcgenError),
     IO(std::io::Error),
 }
@@ -17,6 +21,16 @@ impl Display for CertError {
         match self {
             LoadCertificate => write!(f, "failed to load certificate"),
             LoadPrivateKey => write!(f, "failed to load private key"),
+            KeyMismatch => {
+                write!(f, "private key does not match the certificate")
+            }
+            MissingPassphrase => {
+                write!(f, "private key is encrypted, passphrase required")
+            }
+            DecryptPrivateKey => write!(f, "failed to decrypt private key"),
+            MissingCaKey => {
+                write!(f, "ca.key is missing, the existing ca.pem is unusable")
+            }
             GenCertKey(..) => {
                 write!(f, "failed to generate certificate key pair")
             }
@@ -31,6 +45,10 @@ impl Error for CertError {
         match self {
             LoadCertificate => None,
             LoadPrivateKey => None,
+            KeyMismatch => None,
+            MissingPassphrase => None,
+            DecryptPrivateKey => None,
+            MissingCaKey => None,
             GenCertKey(e) => Some(e),
             IO(e) => Some(e),
         }
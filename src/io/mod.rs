@@ -9,12 +9,23 @@ use crate::transport::{AsyncConnect, AsyncAccept};
 mod copy;
 pub use copy::copy;
 
+mod rewind;
+pub use rewind::Rewind;
+
 pub async fn bidi_copy<L, C>(base: L::Base, lis: Arc<L>, conn: Arc<C>)
 where
     L: AsyncAccept,
     C: AsyncConnect,
 {
-    let (sin, sout) = match try_join!(lis.accept(base), conn.connect()) {
+    let res = if lis.accept_first() {
+        match lis.accept(base).await {
+            Ok(sin) => conn.connect().await.map(|sout| (sin, sout)),
+            Err(e) => Err(e),
+        }
+    } else {
+        try_join!(lis.accept(base), conn.connect())
+    };
+    let (sin, sout) = match res {
         Ok((sin, sout)) => (sin, sout),
         Err(e) => {
             warn!("protocol level handshake error: {}", e);
@@ -85,10 +96,22 @@ pub mod linux_ext {
     use super::*;
     use zero_copy::zero_copy;
     use crate::transport::plain;
+    #[cfg(feature = "tls")]
+    use crate::transport::tls;
     pub async fn bidi_zero_copy(
+        sin: plain::PlainStream,
+        conn: plain::Connector,
+    ) {
+        bidi_zero_copy_with_prefix(&[], sin, conn).await
+    }
+
+    // send the bytes already read from sin first
+    pub async fn bidi_zero_copy_with_prefix(
+        prefix: &[u8],
         mut sin: plain::PlainStream,
         conn: plain::Connector,
     ) {
+        use tokio::io::AsyncWriteExt;
         let mut sout = match conn.connect_plain().await {
             Ok(sout) => sout,
             Err(e) => {
@@ -96,6 +119,10 @@ pub mod linux_ext {
                 return;
             }
         };
+        if let Err(e) = sout.write_all(prefix).await {
+            warn!("forwarding finished, {}", e);
+            return;
+        }
         let (ri, wi) = plain::linux_ext::split(&mut sin);
         let (ro, wo) = plain::linux_ext::split(&mut sout);
 
@@ -105,6 +132,33 @@ pub mod linux_ext {
         }
     }
 
+    // the tls session is left to the kernel
+    #[cfg(feature = "tls")]
+    pub async fn splice_offload(
+        lis: tls::Acceptor<plain::Acceptor>,
+        conn: plain::Connector,
+    ) -> io::Result<()> {
+        let lis = Arc::new(lis);
+        loop {
+            match lis.accept_base().await {
+                Ok((base, addr)) => {
+                    info!("{}[ktls] <-> {}[raw]", &addr, conn.addr());
+                    let lis = lis.clone();
+                    let conn = conn.clone();
+                    tokio::spawn(async move {
+                        match lis.accept_offload(base).await {
+                            Ok(sin) => bidi_zero_copy(sin, conn).await,
+                            Err(e) => {
+                                warn!("protocol level handshake error: {}", e)
+                            }
+                        }
+                    });
+                }
+                Err(e) => warn!("failed to accept[ktls]: {}", e),
+            }
+        }
+    }
+
     pub async fn splice(
         lis: plain::Acceptor,
         conn: plain::Connector,
@@ -25,13 +25,25 @@ fn main() {
     match cmd::scan() {
         CmdInput::Config(c) => start_from_config(c),
         CmdInput::Navigate => cmd::run_navigator(),
+        CmdInput::Schema => cmd::print_schema(),
+        CmdInput::Generate(opts) => cmd::generate(opts),
+        #[cfg(feature = "tls")]
+        CmdInput::ExportCA(dir) => cmd::export_ca(dir),
         CmdInput::None => {}
     }
 }
 
 fn start_from_config(c: String) {
     let config = GlobalConfig::from_config_file(&c);
-    dns::init_resolver(config.dns_mode.into(), config.dns_servers);
+    dns::init_resolver(
+        config.dns_mode,
+        config.dns_servers,
+        config.dns_cache,
+        config.hosts,
+    );
+    if let Some(delay) = config.happy_eyeballs_delay {
+        transport::plain::set_attempt_delay(delay);
+    }
     tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
@@ -1,16 +1,39 @@
 use std::net::SocketAddr;
 
-use crate::dns;
 use crate::error::addr::{Result, AddrError};
 use crate::utils::CommonAddr;
 
-pub fn parse_domain_name(s: &str) -> Option<((String, u16), bool)> {
+// only check the syntax, the name is resolved
+// later when establishing a connection
+fn is_valid_name(s: &str) -> bool {
+    !s.is_empty()
+        && s.len() <= 253
+        && s.trim_end_matches('.').split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+}
+
+pub fn parse_domain_name(s: &str) -> Option<(String, u16)> {
     let mut iter = s.rsplitn(2, ':');
     let port = iter.next()?.parse::<u16>().ok()?;
     let addr = iter.next()?.to_string();
     // check addr
-    if let Ok(ip) = dns::resolve_sync(&addr) {
-        return Some(((addr, port), ip.is_ipv6()));
+    if is_valid_name(&addr) {
+        return Some((addr, port));
+    }
+    None
+}
+
+// srv+_service._proto.name
+pub fn parse_srv_name(s: &str) -> Option<String> {
+    let name = s.strip_prefix("srv+")?.to_string();
+    // check name
+    if is_valid_name(&name) {
+        return Some(name);
     }
     None
 }
@@ -18,13 +41,16 @@ pub fn parse_domain_name(s: &str) -> Option<((String, u16), bool)> {
 pub fn parse_socket_addr(
     addr: &str,
     allow_domain_name: bool,
-) -> Result<(CommonAddr, bool)> {
+) -> Result<CommonAddr> {
     if let Ok(sockaddr) = addr.parse::<SocketAddr>() {
-        return Ok((CommonAddr::SocketAddr(sockaddr), sockaddr.is_ipv6()));
+        return Ok(CommonAddr::SocketAddr(sockaddr));
     };
     if allow_domain_name {
-        if let Some(((addr, port), is_ipv6)) = parse_domain_name(addr) {
-            return Ok((CommonAddr::DomainName(addr, port), is_ipv6));
+        if let Some(name) = parse_srv_name(addr) {
+            return Ok(CommonAddr::SrvName(name));
+        }
+        if let Some((addr, port)) = parse_domain_name(addr) {
+            return Ok(CommonAddr::DomainName(addr, port));
         }
     };
     Err(AddrError::Invalid(addr.to_string()))
@@ -7,19 +7,76 @@ use tokio::task::JoinHandle;
 use crate::config::{EndpointConfig, EpHalfConfig};
 
 mod net;
+mod route;
 mod transport;
-mod common;
+pub mod common;
 
 #[cfg(target_os = "linux")]
 pub fn meet_zero_copy(listen: &EpHalfConfig, remote: &EpHalfConfig) -> bool {
-    use crate::config::TransportConfig;
+    use crate::config::{TransportConfig, TLSConfig};
     matches!(
         (&listen.trans, &remote.trans),
         (TransportConfig::Plain, TransportConfig::Plain)
+    ) && matches!(
+        (&listen.tls, &remote.tls),
+        (TLSConfig::None, TLSConfig::None)
     ) && listen.net.is_zero_copy()
         && remote.net.is_zero_copy()
 }
 
+// tls is terminated by the kernel after the handshake
+#[cfg(all(target_os = "linux", feature = "tls"))]
+pub fn meet_ktls_offload(listen: &EpHalfConfig, remote: &EpHalfConfig) -> bool {
+    use crate::config::{NetConfig, TransportConfig, TLSConfig};
+    matches!(&listen.tls, TLSConfig::Server(x) if x.ktls)
+        && matches!(
+            (&listen.trans, &remote.trans),
+            (TransportConfig::Plain, TransportConfig::Plain)
+        )
+        && matches!(remote.tls, TLSConfig::None)
+        && matches!(listen.net, NetConfig::TCP)
+        && remote.net.is_zero_copy()
+}
+
+// Turn ktls off where the kernel would not take over,
+// so that it does not limit the tls versions.
+#[cfg(all(target_os = "linux", feature = "tls"))]
+fn check_ktls(listen: &mut EpHalfConfig, remote: &EpHalfConfig, routed: bool) {
+    use crate::config::TLSConfig;
+    let offload = !routed && meet_ktls_offload(listen, remote);
+    if let TLSConfig::Server(x) = &mut listen.tls {
+        if x.ktls && !offload {
+            warn!(
+                "ktls is ignored, it requires a plain tcp listener \
+                 and a plain remote, without routes"
+            );
+            x.ktls = false;
+        }
+    }
+}
+
+// Only negotiate the suites the kernel could take over.
+#[cfg(all(target_os = "linux", feature = "tls"))]
+fn offload_suites(
+    lisc: &crate::config::tls::TLSServerConfig,
+    suites: &[rustls::CipherSuite],
+) -> Option<crate::config::tls::TLSServerConfig> {
+    let names: Vec<String> =
+        suites.iter().map(|x| format!("{:?}", x)).collect();
+    let mut lisc = lisc.clone();
+    if lisc.ciphersuites.is_empty() {
+        lisc.ciphersuites = names;
+    } else {
+        lisc.ciphersuites
+            .retain(|x| names.iter().any(|y| y.eq_ignore_ascii_case(x)));
+    }
+    if lisc.ciphersuites.is_empty() {
+        warn!("ktls is not available, no cipher suite could be offloaded");
+        return None;
+    }
+    Some(lisc)
+}
+
 pub async fn run(eps: Vec<EndpointConfig>) {
     let mut count: usize = 0;
     let mut workers: Vec<JoinHandle<io::Result<()>>> =
@@ -27,16 +84,111 @@ pub async fn run(eps: Vec<EndpointConfig>) {
     for ep in eps.into_iter() {
         debug!("load next endpoint");
         // convert into full config
-        let EndpointConfig { listen, remote } = ep;
+        let EndpointConfig {
+            listen,
+            remote,
+            sni_routes,
+            alpn_routes,
+            sniff_routes,
+        } = ep;
         let listen: EpHalfConfig = listen.into();
         let remote: EpHalfConfig = remote.into();
 
+        let routers = [sni_routes.len(), alpn_routes.len(), sniff_routes.len()];
+        if routers.iter().filter(|x| **x != 0).count() > 1 {
+            panic!("only one kind of routes could be used by an endpoint");
+        }
+
+        #[cfg(all(target_os = "linux", feature = "tls"))]
+        let mut listen = listen;
+        #[cfg(all(target_os = "linux", feature = "tls"))]
+        check_ktls(&mut listen, &remote, routers.iter().any(|x| *x != 0));
+
+        // create sni router, the remote is the default route
+        if !sni_routes.is_empty() {
+            route::spawn_sni_router(&mut workers, &listen, &remote, sni_routes);
+            count += 1;
+            info!("new endpoint inited[{}]", count);
+            continue;
+        }
+
+        // create sniff router, the remote is the default route
+        if !sniff_routes.is_empty() {
+            route::spawn_sniff_router(
+                &mut workers,
+                &listen,
+                &remote,
+                sniff_routes,
+            );
+            count += 1;
+            info!("new endpoint inited[{}]", count);
+            continue;
+        }
+
+        // create alpn router, listen.trans and remote are the default route
+        if !alpn_routes.is_empty() {
+            #[cfg(feature = "tls")]
+            route::spawn_alpn_router(
+                &mut workers,
+                listen,
+                &remote,
+                alpn_routes,
+            );
+            #[cfg(not(feature = "tls"))]
+            panic!("alpn routes require tls");
+            count += 1;
+            info!("new endpoint inited[{}]", count);
+            continue;
+        }
+
+        // create zero-copy task, with tls offloaded
+        #[cfg(all(target_os = "linux", feature = "tls"))]
+        if meet_ktls_offload(&listen, &remote) {
+            use crate::dns;
+            use crate::config::TLSConfig;
+            use crate::transport::tls;
+            use crate::io::linux_ext::splice_offload;
+            let lisc = match (tls::probe_offload(), &mut listen.tls) {
+                (Ok(suites), TLSConfig::Server(lisc)) => {
+                    offload_suites(lisc, &suites)
+                }
+                (Err(e), _) => {
+                    warn!("ktls is not available, {}", e);
+                    None
+                }
+                _ => unreachable!(),
+            };
+            match (lisc, &mut listen.tls) {
+                (Some(lisc), _) => {
+                    let lis = lisc.apply_to_lis(net::new_plain_lis(
+                        &listen.addr,
+                        &listen.net,
+                    ));
+                    let conn = net::new_plain_conn(
+                        &remote.addr,
+                        &remote.net,
+                        dns::resolver(remote.dns.as_ref()),
+                    );
+                    workers.push(tokio::spawn(splice_offload(lis, conn)));
+                    continue;
+                }
+                // served in userspace
+                (None, TLSConfig::Server(lisc)) => lisc.ktls = false,
+                _ => unreachable!(),
+            }
+        }
+
         // create zero-copy task
         #[cfg(target_os = "linux")]
         if meet_zero_copy(&listen, &remote) {
+            use crate::dns;
             use crate::io::linux_ext::splice;
             let lis = net::new_plain_lis(&listen.addr, &listen.net);
-            let conn = net::new_plain_conn(&remote.addr, &remote.net);
+            let conn = net::new_plain_conn(
+                &remote.addr,
+                &remote.net,
+                dns::resolver(remote.dns.as_ref()),
+            );
             workers.push(tokio::spawn(splice(lis, conn)));
             continue;
         }
@@ -4,26 +4,31 @@ use tokio::task::JoinHandle;
 
 use super::common;
 use super::transport;
+use crate::dns::{self, Resolver};
 use crate::utils::{must, MaybeQuic};
 use crate::config::{EpHalfConfig, NetConfig};
-use crate::transport::AsyncConnect;
+use crate::transport::{AsyncConnect, AsyncAccept};
 use crate::transport::plain::{self, PlainListener};
 
 // ===== TCP or UDS =====
-pub fn new_plain_conn(addr: &str, net: &NetConfig) -> plain::Connector {
+pub fn new_plain_conn(
+    addr: &str,
+    net: &NetConfig,
+    resolver: Resolver,
+) -> plain::Connector {
     #[cfg(unix)]
     use std::path::PathBuf;
     #[cfg(unix)]
     use crate::utils::CommonAddr;
     match net {
         NetConfig::TCP => {
-            let (sockaddr, _) = must!(common::parse_socket_addr(addr, true));
-            plain::Connector::new(sockaddr)
+            let sockaddr = must!(common::parse_socket_addr(addr, true));
+            plain::Connector::new(sockaddr, resolver)
         }
         #[cfg(all(unix, feature = "uds"))]
         NetConfig::UDS => {
             let path = CommonAddr::UnixSocketPath(PathBuf::from(addr));
-            plain::Connector::new(path)
+            plain::Connector::new(path, resolver)
         }
         _ => unreachable!(),
     }
@@ -36,7 +41,7 @@ pub fn new_plain_lis(addr: &str, net: &NetConfig) -> plain::Acceptor {
     use crate::utils::CommonAddr;
     match net {
         NetConfig::TCP => {
-            let (sockaddr, _) = must!(common::parse_socket_addr(addr, false));
+            let sockaddr = must!(common::parse_socket_addr(addr, false));
             let lis =
                 must!(PlainListener::bind(&sockaddr), "bind {}", &sockaddr);
             info!("bind {}[tcp]", &sockaddr);
@@ -64,14 +69,18 @@ pub mod udp_ext {
     use crate::transport::udp;
     use crate::utils::CommonAddr::*;
 
-    pub fn new_udp_conn(addr: &str, _: &NetConfig) -> udp::Connector {
-        let (sockaddr, _) = must!(common::parse_socket_addr(addr, true));
-        udp::Connector::new(sockaddr)
+    pub fn new_udp_conn(
+        addr: &str,
+        _: &NetConfig,
+        resolver: Resolver,
+    ) -> udp::Connector {
+        let sockaddr = must!(common::parse_socket_addr(addr, true));
+        udp::Connector::new(sockaddr, resolver)
     }
 
     #[cfg(feature = "udp")]
     pub fn new_udp_lis(addr: &str, _: &NetConfig) -> udp::Acceptor {
-        let (sockaddr, _) = must!(common::parse_socket_addr(addr, false));
+        let sockaddr = must!(common::parse_socket_addr(addr, false));
         let socket = match sockaddr {
             SocketAddr(sockaddr) => {
                 block_on(UdpSocket::bind(sockaddr)).unwrap()
@@ -89,6 +98,7 @@ use quic_ext::*;
 pub mod quic_ext {
     use super::*;
     use std::sync::Arc;
+    use log::warn;
     use quinn::{Endpoint, ClientConfig, ServerConfig};
     use crate::utils;
     use crate::transport::quic;
@@ -99,6 +109,7 @@ pub mod quic_ext {
         _: &NetConfig,
         trans: &TransportConfig,
         tlsc: &TLSConfig,
+        resolver: Resolver,
     ) -> quic::Connector {
         // check transport
         let trans = match trans {
@@ -111,30 +122,26 @@ pub mod quic_ext {
             _ => unreachable!(),
         };
 
-        let (sockaddr, is_ipv6) = must!(common::parse_socket_addr(addr, true));
+        let sockaddr = must!(common::parse_socket_addr(addr, true));
         let mut client_tls = tlsc.to_tls();
         let sni = tlsc.set_sni(&mut client_tls, &sockaddr);
 
         let mut client_config = ClientConfig::default();
         // default:
-        // set ciphersuits = QUIC_CIPHER_SUITES
+        // set ciphersuits = QUIC_CIPHER_SUITES(keep the configured ones)
         // set versions = TLSv1_3
         // set enable_early_data = true
-        client_tls.ciphersuites = client_config.crypto.ciphersuites.clone();
+        let quic_suites = &client_config.crypto.ciphersuites;
+        client_tls.ciphersuites.retain(|x| quic_suites.contains(x));
+        if client_tls.ciphersuites.is_empty() {
+            panic!("no cipher suite for quic");
+        }
         client_tls.versions = client_config.crypto.versions.clone();
         client_tls.enable_early_data = client_config.crypto.enable_early_data;
         client_config.crypto = Arc::new(client_tls);
 
-        let bind_addr = if is_ipv6 {
-            utils::empty_sockaddr_v6()
-        } else {
-            utils::empty_sockaddr_v4()
-        };
-
-        let mut builder = Endpoint::builder();
-        builder.default_client_config(client_config);
-        let (ep, _) = must!(builder.bind(&bind_addr), "bind {}", &bind_addr);
-        quic::Connector::new(ep, sockaddr, sni, trans.mux)
+        // endpoints are bound after the remote is resolved
+        quic::Connector::new(client_config, sockaddr, sni, trans.mux, resolver)
     }
 
     pub fn new_quic_raw_lis(
@@ -154,30 +161,85 @@ pub mod quic_ext {
             _ => unreachable!(),
         };
 
-        let (sockaddr, _) = must!(common::parse_socket_addr(addr, false));
+        let sockaddr = must!(common::parse_socket_addr(addr, false));
         let bind_addr = match sockaddr {
             utils::CommonAddr::SocketAddr(ref x) => x,
             _ => unreachable!(),
         };
 
+        // the endpoint keeps the config it is bound with
+        if tlsc.reload_interval != 0 {
+            warn!("tls: certificates of quic listeners are not reloaded");
+        }
         let mut server_tls = tlsc.to_tls();
         let mut server_config = ServerConfig::default();
         // default:
-        // set ciphersuits = QUIC_CIPHER_SUITES
+        // set ciphersuits = QUIC_CIPHER_SUITES(keep the configured ones)
         // set versions = TLSv1_3
         // set max_early_data_size = u32::max_value()
-        server_tls.ciphersuites = server_config.crypto.ciphersuites.clone();
+        let quic_suites = &server_config.crypto.ciphersuites;
+        server_tls.ciphersuites.retain(|x| quic_suites.contains(x));
+        if server_tls.ciphersuites.is_empty() {
+            panic!("no cipher suite for quic");
+        }
         server_tls.versions = server_config.crypto.versions.clone();
         server_tls.max_early_data_size =
             server_config.crypto.max_early_data_size;
+        // replay safety of early data
+        // wait(default): hold it until the client finishes the handshake
+        // accept: forward it before the handshake completes
+        // reject: the client sends it again after the handshake
+        let release_early = match tlsc.early_data.as_str() {
+            "" | "wait" => false,
+            "accept" => true,
+            "reject" => {
+                server_tls.max_early_data_size = 0;
+                false
+            }
+            x => panic!("unknown early data policy: {}", x),
+        };
         server_config.crypto = Arc::new(server_tls);
 
         let mut builder = Endpoint::builder();
         builder.listen(server_config);
         let (_, incoming) = builder.bind(bind_addr).expect("failed to bind");
         info!("bind {}[quic]", &bind_addr);
-        quic::RawAcceptor::new(incoming, sockaddr)
+        quic::RawAcceptor::new(
+            incoming,
+            sockaddr,
+            tlsc.client_auth(),
+            release_early,
+        )
+    }
+}
+
+// ===== DNS =====
+fn new_dns_forwarder(
+    listen: &EpHalfConfig,
+    remote: &EpHalfConfig,
+    resolver: Resolver,
+) -> dns::Forwarder {
+    use crate::utils::CommonAddr;
+    use crate::config::{TransportConfig, TLSConfig};
+    #[cfg(feature = "quic")]
+    use crate::config::TransportConfig::QUIC;
+    if !matches!(remote.trans, TransportConfig::Plain)
+        || !matches!(remote.tls, TLSConfig::None)
+    {
+        panic!("dns remote could not be used with trans or tls");
     }
+    // the addr is only used to identify the forwarder
+    let addr = CommonAddr::DomainName(remote.addr.clone(), 53);
+    // a udp listener passes one query per datagram,
+    // other streams carry length-prefixed queries
+    let datagram = match listen.net {
+        #[cfg(feature = "quic")]
+        NetConfig::UDP if matches!(listen.trans, QUIC(_)) => false,
+        #[cfg(feature = "udp")]
+        NetConfig::UDP => true,
+        _ => false,
+    };
+    dns::Forwarder::new(addr, resolver, datagram)
 }
 
 pub fn spawn_lis_half_with_net<C>(
@@ -222,29 +284,78 @@ pub fn spawn_lis_half_with_net<C>(
             let lis = MaybeQuic::Other(new_udp_lis(&listen.addr, &listen.net));
             transport::spawn_with_trans(workers, listen, remote, lis, conn)
         }
+        DNS => panic!("dns could only be used by remote"),
+    }
+}
+
+// The listener of an endpoint, either bound from the config,
+// or fed by a router.
+pub trait ListenHalf {
+    fn spawn<C>(
+        self,
+        workers: &mut Vec<JoinHandle<io::Result<()>>>,
+        listen: &EpHalfConfig,
+        remote: &EpHalfConfig,
+        conn: C,
+    ) where
+        C: AsyncConnect + 'static;
+}
+
+struct Bind;
+
+impl ListenHalf for Bind {
+    fn spawn<C>(
+        self,
+        workers: &mut Vec<JoinHandle<io::Result<()>>>,
+        listen: &EpHalfConfig,
+        remote: &EpHalfConfig,
+        conn: C,
+    ) where
+        C: AsyncConnect + 'static,
+    {
+        spawn_lis_half_with_net(workers, listen, remote, conn)
+    }
+}
+
+pub struct Given<L>(pub L);
+
+impl<L: AsyncAccept + 'static> ListenHalf for Given<L> {
+    fn spawn<C>(
+        self,
+        workers: &mut Vec<JoinHandle<io::Result<()>>>,
+        listen: &EpHalfConfig,
+        remote: &EpHalfConfig,
+        conn: C,
+    ) where
+        C: AsyncConnect + 'static,
+    {
+        let lis = MaybeQuic::Other(self.0);
+        transport::spawn_with_trans(workers, listen, remote, lis, conn)
     }
 }
 
-pub fn spawn_conn_half_with_net(
+pub fn spawn_conn_half_with_net<H: ListenHalf>(
     workers: &mut Vec<JoinHandle<io::Result<()>>>,
     listen: &EpHalfConfig,
     remote: &EpHalfConfig,
+    lis: H,
 ) {
     use NetConfig::*;
     #[cfg(feature = "quic")]
     use crate::config::TransportConfig::QUIC;
 
     debug!("load remote network[{}]", &remote.net);
+    let resolver = dns::resolver(remote.dns.as_ref());
 
     match &remote.net {
         TCP => {
-            let conn = new_plain_conn(&remote.addr, &remote.net);
-            spawn_lis_half_with_net(workers, listen, remote, conn)
+            let conn = new_plain_conn(&remote.addr, &remote.net, resolver);
+            lis.spawn(workers, listen, remote, conn)
         }
         #[cfg(all(unix, feature = "uds"))]
         UDS => {
-            let conn = new_plain_conn(&remote.addr, &remote.net);
-            spawn_lis_half_with_net(workers, listen, remote, conn)
+            let conn = new_plain_conn(&remote.addr, &remote.net, resolver);
+            lis.spawn(workers, listen, remote, conn)
         }
         #[cfg(feature = "quic")]
         UDP if matches!(&remote.trans, QUIC(_)) => {
@@ -253,13 +364,18 @@ pub fn spawn_conn_half_with_net(
                 &remote.net,
                 &remote.trans,
                 &remote.tls,
+                resolver,
             );
-            spawn_lis_half_with_net(workers, listen, remote, conn)
+            lis.spawn(workers, listen, remote, conn)
         }
         #[cfg(feature = "udp")]
         UDP => {
-            let conn = new_udp_conn(&remote.addr, &remote.net);
-            spawn_lis_half_with_net(workers, listen, remote, conn)
+            let conn = new_udp_conn(&remote.addr, &remote.net, resolver);
+            lis.spawn(workers, listen, remote, conn)
+        }
+        DNS => {
+            let conn = new_dns_forwarder(listen, remote, resolver);
+            lis.spawn(workers, listen, remote, conn)
         }
     }
 }
@@ -269,5 +385,5 @@ pub fn spawn_with_net(
     listen: &EpHalfConfig,
     remote: &EpHalfConfig,
 ) {
-    spawn_conn_half_with_net(workers, listen, remote)
+    spawn_conn_half_with_net(workers, listen, remote, Bind)
 }
@@ -0,0 +1,397 @@
+use std::io;
+use std::sync::Arc;
+use std::net::SocketAddr;
+use std::future::Future;
+use std::collections::HashSet;
+
+use log::{info, warn, debug};
+use futures::channel::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
+
+use super::net;
+use crate::dns;
+use crate::io::Rewind;
+use crate::utils::{self, ClientHelloInfo, Protocol};
+use crate::config::{EpHalfConfig, SniRouteConfig, SniffRouteConfig};
+use crate::config::{NetConfig, TransportConfig, TLSConfig};
+use crate::transport::{chan, plain, AsyncAccept, AsyncConnect};
+
+type Routed = Rewind<plain::PlainStream>;
+
+enum Target {
+    // raw tcp on both sides
+    #[cfg(target_os = "linux")]
+    Splice(plain::Connector),
+    // handed over to an endpoint with its own remote
+    Chan(UnboundedSender<(Routed, SocketAddr)>),
+}
+
+struct Route {
+    names: HashSet<String>,
+    alpns: Vec<Vec<u8>>,
+    target: Target,
+}
+
+impl Route {
+    fn matches(&self, hello: &ClientHelloInfo) -> bool {
+        let name = match &hello.sni {
+            Some(x) => x,
+            None => return false,
+        };
+        utils::match_server_name(&self.names, name)
+            && (self.alpns.is_empty()
+                || hello.alpns.iter().any(|x| self.alpns.contains(x)))
+    }
+}
+
+struct Router {
+    routes: Vec<Route>,
+    // non-tls, or no route matches
+    default: Target,
+}
+
+// Peek at the client hello and pass the connection through,
+// the tls session is terminated by the remote.
+pub fn spawn_sni_router(
+    workers: &mut Vec<JoinHandle<io::Result<()>>>,
+    listen: &EpHalfConfig,
+    remote: &EpHalfConfig,
+    routes: Vec<SniRouteConfig>,
+) {
+    if !matches!(listen.trans, TransportConfig::Plain)
+        || !matches!(listen.tls, TLSConfig::None)
+    {
+        panic!("sni routes require a plain listener");
+    }
+    if !is_stream_net(&listen.net) {
+        panic!("sni routes require a tcp or uds listener");
+    }
+    let lis = net::new_plain_lis(&listen.addr, &listen.net);
+    let routes = routes
+        .into_iter()
+        .map(|r| Route {
+            names: r
+                .server_names
+                .iter()
+                .map(|x| x.to_ascii_lowercase())
+                .collect(),
+            alpns: r.alpns.iter().map(|x| x.as_bytes().to_vec()).collect(),
+            target: new_target(workers, &lis, listen, &r.remote.into()),
+        })
+        .collect();
+    let default = new_target(workers, &lis, listen, remote);
+    let router = Arc::new(Router { routes, default });
+    workers.push(tokio::spawn(route(lis, move |stream, addr| {
+        dispatch(stream, addr, router.clone())
+    })));
+}
+
+fn new_target(
+    workers: &mut Vec<JoinHandle<io::Result<()>>>,
+    lis: &plain::Acceptor,
+    listen: &EpHalfConfig,
+    remote: &EpHalfConfig,
+) -> Target {
+    #[cfg(target_os = "linux")]
+    if super::meet_zero_copy(listen, remote) {
+        let resolver = dns::resolver(remote.dns.as_ref());
+        let conn = net::new_plain_conn(&remote.addr, &remote.net, resolver);
+        return Target::Splice(conn);
+    }
+    let (send, lis) = chan::Acceptor::new(lis.addr().clone());
+    net::spawn_conn_half_with_net(workers, listen, remote, net::Given(lis));
+    Target::Chan(send)
+}
+
+// the first bytes are read from a stream
+fn is_stream_net(net: &NetConfig) -> bool {
+    match net {
+        NetConfig::TCP => true,
+        #[cfg(all(unix, feature = "uds"))]
+        NetConfig::UDS => true,
+        _ => false,
+    }
+}
+
+async fn route<F, Fut>(lis: plain::Acceptor, dispatch: F) -> io::Result<()>
+where
+    F: Fn(plain::PlainStream, SocketAddr) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    loop {
+        match lis.accept_base().await {
+            Ok((stream, addr)) => {
+                tokio::spawn(dispatch(stream, addr));
+            }
+            Err(e) => warn!("failed to accept[tcp]: {}", e),
+        }
+    }
+}
+
+impl Target {
+    // replay what is read, then relay the rest
+    async fn forward(
+        &self,
+        buf: Vec<u8>,
+        stream: plain::PlainStream,
+        addr: SocketAddr,
+    ) {
+        match self {
+            #[cfg(target_os = "linux")]
+            Target::Splice(conn) => {
+                use crate::io::linux_ext::bidi_zero_copy_with_prefix;
+                info!("{}[raw] <-> {}[raw]", &addr, conn.addr());
+                bidi_zero_copy_with_prefix(&buf, stream, conn.clone()).await
+            }
+            Target::Chan(send) => {
+                let _ = send.unbounded_send((Rewind::new(buf, stream), addr));
+            }
+        }
+    }
+}
+
+async fn dispatch(
+    mut stream: plain::PlainStream,
+    addr: SocketAddr,
+    router: Arc<Router>,
+) {
+    let mut buf = Vec::new();
+    // None if not tls, or the hello is not sent in time
+    let hello = match utils::read_client_hello(&mut stream, &mut buf).await {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("sni route: failed to read client hello, {}", e);
+            return;
+        }
+    };
+    let route = hello
+        .as_ref()
+        .and_then(|x| router.routes.iter().find(|r| r.matches(x)));
+    debug!(
+        "sni route: {} <- {}",
+        hello.as_ref().and_then(|x| x.sni.as_deref()).unwrap_or("-"),
+        &addr
+    );
+    let target = route.map_or(&router.default, |r| &r.target);
+    target.forward(buf, stream, addr).await
+}
+
+// ===== Sniff =====
+struct SniffRoute {
+    protocol: Protocol,
+    target: Target,
+}
+
+struct SniffRouter {
+    routes: Vec<SniffRoute>,
+    // unknown protocols, or no route matches
+    default: Target,
+}
+
+// Tell the protocol from the first bytes, so that one port
+// could serve several chains.
+pub fn spawn_sniff_router(
+    workers: &mut Vec<JoinHandle<io::Result<()>>>,
+    listen: &EpHalfConfig,
+    remote: &EpHalfConfig,
+    routes: Vec<SniffRouteConfig>,
+) {
+    if !matches!(listen.trans, TransportConfig::Plain)
+        || !matches!(listen.tls, TLSConfig::None)
+    {
+        panic!("sniff routes require a plain listener");
+    }
+    if !is_stream_net(&listen.net) {
+        panic!("sniff routes require a tcp or uds listener");
+    }
+    let lis = net::new_plain_lis(&listen.addr, &listen.net);
+    let routes = routes
+        .into_iter()
+        .map(|r| {
+            let protocol = match r.protocol.as_str() {
+                "tls" => Protocol::TLS,
+                "h2c" => Protocol::H2C,
+                "http" => Protocol::HTTP,
+                x => panic!("unknown sniffed protocol: {}", x),
+            };
+            // the chain after the router
+            let half = EpHalfConfig {
+                addr: listen.addr.clone(),
+                net: listen.net,
+                trans: r.trans,
+                tls: r.tls,
+                ..Default::default()
+            };
+            SniffRoute {
+                protocol,
+                target: new_target(workers, &lis, &half, &r.remote.into()),
+            }
+        })
+        .collect();
+    let default = new_target(workers, &lis, listen, remote);
+    let router = Arc::new(SniffRouter { routes, default });
+    workers.push(tokio::spawn(route(lis, move |stream, addr| {
+        dispatch_sniff(stream, addr, router.clone())
+    })));
+}
+
+async fn dispatch_sniff(
+    mut stream: plain::PlainStream,
+    addr: SocketAddr,
+    router: Arc<SniffRouter>,
+) {
+    let mut buf = Vec::new();
+    let protocol = match utils::sniff(&mut stream, &mut buf).await {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("sniff route: failed to read, {}", e);
+            return;
+        }
+    };
+    debug!("sniff route: {} <- {}", protocol.as_str(), &addr);
+    let route = router.routes.iter().find(|r| r.protocol == protocol);
+    let target = route.map_or(&router.default, |r| &r.target);
+    target.forward(buf, stream, addr).await
+}
+
+// ===== ALPN =====
+#[cfg(feature = "tls")]
+pub use alpn_ext::spawn_alpn_router;
+#[cfg(feature = "tls")]
+mod alpn_ext {
+    use super::*;
+    use rustls::Session;
+    use crate::config::{AlpnRouteConfig, NetConfig};
+    use crate::transport::tls;
+
+    type Terminated = tls::ServerTLSStream<Routed>;
+
+    type Sender = UnboundedSender<(Terminated, SocketAddr)>;
+
+    struct AlpnRoute {
+        alpns: Vec<Vec<u8>>,
+        send: Sender,
+    }
+
+    struct AlpnRouter {
+        routes: Vec<AlpnRoute>,
+        // no alpn, or no route matches
+        default: Sender,
+    }
+
+    // Terminate tls, then hand the connection over to
+    // the transport and remote of the negotiated protocol.
+    pub fn spawn_alpn_router(
+        workers: &mut Vec<JoinHandle<io::Result<()>>>,
+        listen: EpHalfConfig,
+        remote: &EpHalfConfig,
+        routes: Vec<AlpnRouteConfig>,
+    ) {
+        let EpHalfConfig {
+            addr,
+            net,
+            trans,
+            tls,
+            ..
+        } = listen;
+        let tlsc = match tls {
+            TLSConfig::Server(x) => x,
+            _ => panic!("alpn routes require a tls listener"),
+        };
+        match net {
+            NetConfig::TCP => {}
+            #[cfg(all(unix, feature = "uds"))]
+            NetConfig::UDS => {}
+            _ => panic!("alpn routes require a tcp or uds listener"),
+        }
+        let lis = tlsc.apply_to_lis(net::new_plain_lis(&addr, &net));
+
+        // the chain after tls, fed by the router
+        let mut new_sender = |trans, remote: &EpHalfConfig| -> Sender {
+            let half = EpHalfConfig {
+                addr: addr.clone(),
+                net,
+                trans,
+                ..Default::default()
+            };
+            let (send, chan) = chan::Acceptor::new(lis.addr().clone());
+            net::spawn_conn_half_with_net(
+                workers,
+                &half,
+                remote,
+                net::Given(chan),
+            );
+            send
+        };
+        let routes = routes
+            .into_iter()
+            .map(|r| {
+                if let Some(x) =
+                    r.alpns.iter().find(|x| !tlsc.alpns.contains(x))
+                {
+                    panic!("alpn {} is not advertised by the listener", x);
+                }
+                AlpnRoute {
+                    alpns: r
+                        .alpns
+                        .iter()
+                        .map(|x| x.as_bytes().to_vec())
+                        .collect(),
+                    send: new_sender(r.trans, &r.remote.into()),
+                }
+            })
+            .collect();
+        let default = new_sender(trans, remote);
+        let router = AlpnRouter { routes, default };
+        workers.push(tokio::spawn(route_alpn(lis, Arc::new(router))));
+    }
+
+    async fn route_alpn(
+        lis: tls::Acceptor<plain::Acceptor>,
+        router: Arc<AlpnRouter>,
+    ) -> io::Result<()> {
+        let lis = Arc::new(lis);
+        loop {
+            match lis.accept_base().await {
+                Ok((base, addr)) => {
+                    tokio::spawn(dispatch_alpn(
+                        base,
+                        addr,
+                        lis.clone(),
+                        router.clone(),
+                    ));
+                }
+                Err(e) => warn!("failed to accept[tcp]: {}", e),
+            }
+        }
+    }
+
+    async fn dispatch_alpn(
+        base: plain::PlainStream,
+        addr: SocketAddr,
+        lis: Arc<tls::Acceptor<plain::Acceptor>>,
+        router: Arc<AlpnRouter>,
+    ) {
+        let stream = match lis.accept(base).await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("alpn route: handshake error, {}", e);
+                return;
+            }
+        };
+        let alpn = stream.get_ref().1.get_alpn_protocol();
+        debug!(
+            "alpn route: {} <- {}",
+            alpn.map_or("-".into(), String::from_utf8_lossy),
+            &addr
+        );
+        let route = alpn.and_then(|x| {
+            router
+                .routes
+                .iter()
+                .find(|r| r.alpns.iter().any(|a| a == x))
+        });
+        let send = route.map_or(&router.default, |r| &r.send);
+        let _ = send.unbounded_send((stream, addr));
+    }
+}
@@ -128,6 +128,16 @@ fn spawn_with_tls<L, C>(
     debug!("load listen tls[{}]", &listen.tls);
     debug!("load remote tls[{}]", &remote.tls);
 
+    // never fall back to plaintext silently
+    #[cfg(feature = "tls")]
+    if matches!(&listen.tls, Client(_)) {
+        panic!("listen tls requires a server config, e.g. cert and key");
+    }
+    #[cfg(feature = "tls")]
+    if matches!(&remote.tls, Server(_)) {
+        panic!("remote tls requires a client config, e.g. sni or role");
+    }
+
     match &listen.tls {
         #[cfg(feature = "tls")]
         Server(lisc) if !is_quic_lis => match &remote.tls {
@@ -0,0 +1,55 @@
+use std::io::{Result, Error, ErrorKind};
+use std::net::SocketAddr;
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use futures::lock::Mutex;
+use futures::channel::mpsc::{self, UnboundedSender, UnboundedReceiver};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::utils::CommonAddr;
+use crate::transport::{AsyncAccept, Transport};
+
+// Accept connections handed over by a router, which has
+// already accepted and inspected them.
+pub struct Acceptor<S> {
+    addr: CommonAddr,
+    recv: Mutex<UnboundedReceiver<(S, SocketAddr)>>,
+}
+
+impl<S> Acceptor<S> {
+    pub fn new(addr: CommonAddr) -> (UnboundedSender<(S, SocketAddr)>, Self) {
+        let (send, recv) = mpsc::unbounded();
+        let lis = Acceptor {
+            addr,
+            recv: Mutex::new(recv),
+        };
+        (send, lis)
+    }
+}
+
+#[async_trait]
+impl<S> AsyncAccept for Acceptor<S>
+where
+    S: AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static,
+{
+    const TRANS: Transport = Transport::TCP;
+
+    const SCHEME: &'static str = "tcp";
+
+    type IO = S;
+
+    type Base = S;
+
+    #[inline]
+    fn addr(&self) -> &CommonAddr { &self.addr }
+
+    async fn accept_base(&self) -> Result<(Self::Base, SocketAddr)> {
+        self.recv.lock().await.next().await.ok_or_else(|| {
+            Error::new(ErrorKind::ConnectionAborted, "router closed")
+        })
+    }
+
+    #[inline]
+    async fn accept(&self, base: Self::Base) -> Result<Self::IO> { Ok(base) }
+}
@@ -56,6 +56,9 @@ where
     #[inline]
     fn addr(&self) -> &CommonAddr { self.lis.addr() }
 
+    #[inline]
+    fn accept_first(&self) -> bool { self.lis.accept_first() }
+
     #[inline]
     async fn accept_base(&self) -> Result<(Self::Base, SocketAddr)> {
         self.lis.accept_base().await
@@ -104,6 +107,9 @@ where
     #[inline]
     fn addr(&self) -> &CommonAddr { self.lis.addr() }
 
+    #[inline]
+    fn accept_first(&self) -> bool { self.lis.accept_first() }
+
     #[inline]
     async fn accept_base(&self) -> Result<(Self::Base, SocketAddr)> {
         self.lis.accept_base().await
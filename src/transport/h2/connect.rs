@@ -10,6 +10,7 @@ use http::{Uri, Version, Request};
 use h2::client::{self, SendRequest};
 
 use super::H2Stream;
+use crate::dns::{Resolver, Watcher};
 use crate::transport::{AsyncConnect, Transport};
 use crate::utils::CommonAddr;
 
@@ -20,6 +21,7 @@ pub struct Connector<T: AsyncConnect> {
     max_concurrent: usize,
     count: AtomicUsize,
     channel: RwLock<Option<SendRequest<Bytes>>>,
+    watcher: Watcher,
 }
 
 impl<T: AsyncConnect> Connector<T> {
@@ -30,6 +32,7 @@ impl<T: AsyncConnect> Connector<T> {
         max_concurrent: usize,
     ) -> Self {
         let authority = cc.addr().to_string();
+        let watcher = Watcher::new(cc.resolver().clone());
         let max_concurrent = if max_concurrent == 0 {
             1000
         } else {
@@ -47,6 +50,7 @@ impl<T: AsyncConnect> Connector<T> {
             max_concurrent,
             count: AtomicUsize::new(1),
             channel: RwLock::new(None),
+            watcher,
         }
     }
 }
@@ -65,6 +69,9 @@ impl<T: AsyncConnect> AsyncConnect for Connector<T> {
     #[inline]
     fn addr(&self) -> &CommonAddr { self.cc.addr() }
 
+    #[inline]
+    fn resolver(&self) -> &Resolver { self.cc.resolver() }
+
     #[inline]
     fn clear_reuse(&self) { *self.channel.write().unwrap() = None }
 
@@ -126,7 +133,13 @@ async fn new_client<T: AsyncConnect>(
 ) -> Result<SendRequest<Bytes>> {
     // reuse existed connection
     trace!("h2 init new client");
-    let channel = (*cc.channel.read().unwrap()).clone();
+    let mut channel = (*cc.channel.read().unwrap()).clone();
+    // retire the connection if the remote has moved
+    if channel.is_some() && cc.watcher.changed(cc.cc.addr()).await {
+        debug!("h2 retire connection -> {}", cc.cc.addr());
+        cc.clear_reuse();
+        channel = None;
+    }
     if let Some(channel) = channel {
         let count = cc.count.load(Ordering::Relaxed);
         trace!("h2 reusable, current mux = {}", count);
@@ -141,6 +154,7 @@ async fn new_client<T: AsyncConnect>(
 
     // establish a new connection
     let stream = cc.cc.connect().await?;
+    cc.watcher.reset(cc.cc.addr()).await;
     debug!("h2 connect[new] ->");
 
     let (client, conn) = client::Builder::new()
@@ -2,10 +2,13 @@ use std::io;
 use std::net::SocketAddr;
 use async_trait::async_trait;
 use tokio::io::{AsyncRead, AsyncWrite};
+use crate::dns::Resolver;
 use crate::utils::CommonAddr;
 
 pub mod plain;
 
+pub mod chan;
+
 #[cfg(feature = "ws")]
 pub mod ws;
 
@@ -31,6 +34,7 @@ pub enum Transport {
     H2,
     UDP,
     QUIC,
+    DNS,
 }
 
 #[async_trait]
@@ -39,6 +43,7 @@ pub trait AsyncConnect: Send + Sync + Unpin {
     const SCHEME: &'static str;
     type IO: AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static;
     fn addr(&self) -> &CommonAddr;
+    fn resolver(&self) -> &Resolver;
     // only for protocols impl mux
     fn clear_reuse(&self);
     async fn connect(&self) -> io::Result<Self::IO>;
@@ -51,6 +56,9 @@ pub trait AsyncAccept: Send + Sync + Unpin {
     type IO: AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static;
     type Base: AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static;
     fn addr(&self) -> &CommonAddr;
+    // finish the handshake before connecting to the remote,
+    // e.g. the connection could be handed to a fallback
+    fn accept_first(&self) -> bool { false }
     // initial accept
     async fn accept_base(&self) -> io::Result<(Self::Base, SocketAddr)>;
     // protocol handshake
@@ -1,33 +1,52 @@
 use std::io;
-use std::net::SocketAddr;
+use std::future::Future;
+use std::time::Duration;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use log::debug;
 use async_trait::async_trait;
+use futures::StreamExt;
+use futures::stream::FuturesUnordered;
 
 use tokio::net::TcpStream;
+use tokio::time::timeout;
 #[cfg(all(unix, feature = "uds"))]
 use tokio::net::UnixStream;
 
 use super::PlainStream;
-use crate::dns;
-use crate::utils::CommonAddr;
+use crate::dns::Resolver;
+use crate::utils::{CommonAddr, HAPPY_EYEBALLS_DELAY, MIN_HAPPY_EYEBALLS_DELAY};
 use crate::transport::{AsyncConnect, Transport};
 
+static ATTEMPT_DELAY: AtomicU64 = AtomicU64::new(HAPPY_EYEBALLS_DELAY);
+
+// connection attempt delay(ms) of happy eyeballs,
+// no less than the minimum of RFC 8305
+pub fn set_attempt_delay(delay: u64) {
+    let delay = delay.max(MIN_HAPPY_EYEBALLS_DELAY);
+    ATTEMPT_DELAY.store(delay, Ordering::Relaxed);
+}
+
 #[derive(Clone)]
 pub struct Connector {
     addr: CommonAddr,
+    resolver: Resolver,
 }
 
 impl Connector {
-    pub fn new(addr: CommonAddr) -> Self { Connector { addr } }
+    pub fn new(addr: CommonAddr, resolver: Resolver) -> Self {
+        Connector { addr, resolver }
+    }
 
     pub async fn connect_plain(&self) -> io::Result<PlainStream> {
         let stream = match &self.addr {
             CommonAddr::DomainName(addr, port) => {
-                let ip = dns::resolve_async(addr).await?;
-                let sockaddr = SocketAddr::new(ip, *port);
-                debug!("tcp connect -> {}", &sockaddr);
-                PlainStream::TCP(TcpStream::connect(sockaddr).await?)
+                let ips = self.resolver.resolve_both(addr).await?;
+                PlainStream::TCP(happy_eyeballs(ips, *port).await?)
+            }
+            CommonAddr::SrvName(name) => {
+                PlainStream::TCP(self.connect_srv(name).await?)
             }
             CommonAddr::SocketAddr(sockaddr) => {
                 debug!("tcp connect -> {}", sockaddr);
@@ -42,6 +61,104 @@ impl Connector {
         stream.set_no_delay(true)?;
         Ok(stream)
     }
+
+    // try each target in order of preference
+    async fn connect_srv(&self, name: &str) -> io::Result<TcpStream> {
+        let mut last_err = None;
+        for (target, port) in self.resolver.lookup_srv(name).await? {
+            debug!("srv {} -> {}:{}", name, &target, port);
+            let result = match self.resolver.resolve_both(&target).await {
+                Ok(ips) => happy_eyeballs(ips, port).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap())
+    }
+}
+
+// RFC 8305
+// start the next attempt if the previous one fails,
+// or does not complete within the attempt delay
+async fn happy_eyeballs(ips: Vec<IpAddr>, port: u16) -> io::Result<TcpStream> {
+    let delay = Duration::from_millis(ATTEMPT_DELAY.load(Ordering::Relaxed));
+    let addrs = interleave(ips)
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, port));
+    race(addrs, delay, |sockaddr| async move {
+        debug!("tcp connect -> {}", &sockaddr);
+        TcpStream::connect(sockaddr).await
+    })
+    .await
+}
+
+async fn race<T, F, Fut>(
+    addrs: impl Iterator<Item = SocketAddr>,
+    delay: Duration,
+    connect: F,
+) -> io::Result<T>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let mut addrs = addrs.fuse();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = None;
+    let mut exhausted = false;
+
+    loop {
+        // nothing left to start, wait for the pending attempts
+        let next = if exhausted {
+            Ok(attempts.next().await)
+        } else {
+            timeout(delay, attempts.next()).await
+        };
+        match next {
+            Ok(Some(Ok(stream))) => return Ok(stream),
+            // failed, or no pending attempt
+            Ok(x) => {
+                if let Some(Err(e)) = x {
+                    debug!("tcp connect failed, {}", &e);
+                    last_err = Some(e);
+                }
+                match addrs.next() {
+                    Some(sockaddr) => attempts.push(connect(sockaddr)),
+                    None if attempts.is_empty() => {
+                        return Err(last_err.unwrap_or_else(|| {
+                            io::Error::new(
+                                io::ErrorKind::NotFound,
+                                "no address",
+                            )
+                        }))
+                    }
+                    None => exhausted = true,
+                }
+            }
+            // attempt delay elapsed
+            Err(_) => match addrs.next() {
+                Some(sockaddr) => attempts.push(connect(sockaddr)),
+                None => exhausted = true,
+            },
+        }
+    }
+}
+
+// ipv6 first, then alternate between address families
+fn interleave(ips: Vec<IpAddr>) -> Vec<IpAddr> {
+    let (v6, v4): (Vec<IpAddr>, Vec<IpAddr>) =
+        ips.into_iter().partition(|ip| ip.is_ipv6());
+    let mut v6 = v6.into_iter();
+    let mut v4 = v4.into_iter();
+    let mut ips = Vec::new();
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return ips,
+            (x, y) => ips.extend(x.into_iter().chain(y)),
+        }
+    }
 }
 
 #[async_trait]
@@ -55,6 +172,9 @@ impl AsyncConnect for Connector {
     #[inline]
     fn addr(&self) -> &CommonAddr { &self.addr }
 
+    #[inline]
+    fn resolver(&self) -> &Resolver { &self.resolver }
+
     fn clear_reuse(&self) {}
 
     #[inline]
@@ -62,3 +182,150 @@ impl AsyncConnect for Connector {
         self.connect_plain().await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::time::{sleep, Instant};
+
+    fn refused() -> io::Error {
+        io::Error::new(io::ErrorKind::ConnectionRefused, "refused")
+    }
+
+    fn ips(s: &[&str]) -> Vec<IpAddr> {
+        s.iter().map(|x| x.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn interleave_families() {
+        let mixed = ips(&["1.1.1.1", "2.2.2.2", "3.3.3.3", "::1", "::2"]);
+        let expected = ips(&["::1", "1.1.1.1", "::2", "2.2.2.2", "3.3.3.3"]);
+        assert_eq!(interleave(mixed), expected);
+        assert_eq!(interleave(ips(&["1.1.1.1"])), ips(&["1.1.1.1"]));
+        assert!(interleave(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn race_staggered_attempts() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .start_paused(true)
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let delay = Duration::from_millis(250);
+            let addrs: Vec<SocketAddr> = ["[::1]:1", "127.0.0.1:2", "[::2]:3"]
+                .iter()
+                .map(|x| x.parse().unwrap())
+                .collect();
+            let start = Instant::now();
+            let started = Arc::new(Mutex::new(Vec::new()));
+            let connect = |sockaddr: SocketAddr| {
+                let started = started.clone();
+                async move {
+                    let elapsed = start.elapsed();
+                    started.lock().unwrap().push((sockaddr.port(), elapsed));
+                    match sockaddr.port() {
+                        // never completes in time
+                        1 => sleep(Duration::from_secs(10)).await,
+                        // fails before the attempt delay
+                        2 => sleep(Duration::from_millis(10)).await,
+                        _ => return Ok(sockaddr),
+                    }
+                    Err(refused())
+                }
+            };
+
+            let sockaddr = race(addrs.into_iter(), delay, connect).await;
+            assert_eq!(sockaddr.unwrap().port(), 3);
+            let ms = |x: u64| Duration::from_millis(x);
+            // the next attempt starts once the delay elapses,
+            // or right after the previous one fails
+            assert_eq!(
+                *started.lock().unwrap(),
+                vec![(1, ms(0)), (2, ms(250)), (3, ms(260))]
+            );
+        });
+    }
+
+    #[test]
+    fn race_all_failed() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .start_paused(true)
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let addrs = vec!["127.0.0.1:1".parse().unwrap()];
+            let connect = |_| async { Err::<(), _>(refused()) };
+            let err = race(addrs.into_iter(), Duration::ZERO, connect)
+                .await
+                .unwrap_err();
+            assert_eq!(err.to_string(), "refused");
+            let err = race(Vec::new().into_iter(), Duration::ZERO, connect)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        });
+    }
+
+    #[test]
+    fn race_wait_pending() {
+        use std::sync::atomic::AtomicUsize;
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .start_paused(true)
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            // all attempts are started at once, then only awaited
+            let polls = Arc::new(AtomicUsize::new(0));
+            let connect = |sockaddr: SocketAddr| {
+                let polls = polls.clone();
+                let mut done = Box::pin(sleep(Duration::from_secs(1)));
+                futures::future::poll_fn(move |cx| {
+                    let n = polls.fetch_add(1, Ordering::Relaxed);
+                    assert!(n < 100, "busy loop");
+                    done.as_mut().poll(cx).map(|_| Ok(sockaddr))
+                })
+            };
+            let addrs = vec!["127.0.0.1:1".parse().unwrap()];
+            let sockaddr = race(addrs.into_iter(), Duration::ZERO, connect)
+                .await
+                .unwrap();
+            assert_eq!(sockaddr.port(), 1);
+        });
+    }
+
+    #[test]
+    fn race_resolved_families() {
+        use std::collections::HashMap;
+        use crate::config::DnsMode;
+        use crate::dns::mock;
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let mut answers = HashMap::new();
+            answers.insert(String::from("dual.test"), ips(&["1.1.1.1", "::1"]));
+            let server = mock::serve(answers).await;
+            // ipv4 is preferred by default, ipv6 is still raced first
+            let resolver = mock::resolver(DnsMode::default(), server);
+            let resolved = resolver.resolve_both("dual.test").await.unwrap();
+            let addrs = interleave(resolved)
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, 443));
+
+            let started = Arc::new(Mutex::new(Vec::new()));
+            let connect = |sockaddr: SocketAddr| {
+                started.lock().unwrap().push(sockaddr.ip());
+                async { Err::<(), _>(refused()) }
+            };
+            let delay = Duration::from_millis(HAPPY_EYEBALLS_DELAY);
+            assert!(race(addrs, delay, connect).await.is_err());
+            assert_eq!(*started.lock().unwrap(), ips(&["::1", "1.1.1.1"]));
+        });
+    }
+}
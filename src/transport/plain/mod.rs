@@ -4,4 +4,4 @@ mod connect;
 
 pub use stream::*;
 pub use accept::{Acceptor, PlainListener};
-pub use connect::Connector;
+pub use connect::{Connector, set_attempt_delay};
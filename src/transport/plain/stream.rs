@@ -61,7 +61,7 @@ pub use linux_ext::*;
 pub mod linux_ext {
     use super::*;
     use std::io::{Error, ErrorKind};
-    use tokio::io::Interest;
+    use tokio::io::{Interest, Ready};
 
     #[inline]
     pub fn split(x: &mut PlainStream) -> (ReadHalf, WriteHalf) {
@@ -91,6 +91,16 @@ pub mod linux_ext {
         }
     }
 
+    #[cfg(feature = "tls")]
+    #[inline]
+    pub async fn ready(x: &PlainStream, interest: Interest) -> Result<Ready> {
+        match x {
+            PlainStream::TCP(x) => x.ready(interest).await,
+            #[cfg(feature = "uds")]
+            PlainStream::UDS(x) => x.ready(interest).await,
+        }
+    }
+
     #[inline]
     pub async fn writable(x: &PlainStream) -> Result<()> {
         match x {
@@ -6,24 +6,64 @@ use futures::StreamExt;
 use log::{warn, info, debug};
 use async_trait::async_trait;
 
-use quinn::{NewConnection, Incoming, IncomingBiStreams};
+use quinn::{Connecting, NewConnection, Incoming, IncomingBiStreams};
 
 use super::QuicStream;
-use crate::utils::{self, CommonAddr};
+use crate::utils::{self, CommonAddr, ClientAuth};
 use crate::transport::{AsyncConnect, AsyncAccept, Transport};
 
 pub struct Acceptor<C> {
     cc: Arc<C>,
     lis: Incoming,
     addr: CommonAddr,
+    client_auth: Option<ClientAuth>,
+    release_early: bool,
 }
 
 impl<C> Acceptor<C> {
-    pub fn new(cc: Arc<C>, lis: Incoming, addr: CommonAddr) -> Self {
-        Acceptor { cc, lis, addr }
+    pub fn new(
+        cc: Arc<C>,
+        lis: Incoming,
+        addr: CommonAddr,
+        client_auth: Option<ClientAuth>,
+        release_early: bool,
+    ) -> Self {
+        Acceptor {
+            cc,
+            lis,
+            addr,
+            client_auth,
+            release_early,
+        }
     }
 }
 
+// early data is released before the handshake completes
+// if allowed, unless the client needs to be verified
+async fn handshake(
+    connecting: Connecting,
+    client_auth: &Option<ClientAuth>,
+    release_early: bool,
+) -> Result<NewConnection> {
+    let client_auth = match client_auth {
+        Some(x) => x,
+        None if !release_early => return Ok(connecting.await?),
+        None => {
+            return Ok(match connecting.into_0rtt() {
+                Ok((new_conn, _)) => new_conn,
+                Err(connecting) => connecting.await?,
+            })
+        }
+    };
+    let new_conn = connecting.await?;
+    let certs: Option<Vec<_>> = new_conn
+        .connection
+        .peer_identity()
+        .map(|x| x.iter().cloned().collect());
+    client_auth.check(certs.as_deref())?;
+    Ok(new_conn)
+}
+
 // Single Connection
 #[async_trait]
 impl AsyncAccept for Acceptor<()> {
@@ -44,11 +84,9 @@ impl AsyncAccept for Acceptor<()> {
             Error::new(ErrorKind::ConnectionAborted, "connection abort")
         })?;
 
-        // early data
-        let new_conn = match connecting.into_0rtt() {
-            Ok((new_conn, _)) => new_conn,
-            Err(connecting) => connecting.await?,
-        };
+        let new_conn =
+            handshake(connecting, &self.client_auth, self.release_early)
+                .await?;
 
         let NewConnection {
             connection: x,
@@ -91,11 +129,9 @@ where
             Error::new(ErrorKind::ConnectionAborted, "connection abort")
         })?;
 
-        // early data
-        let new_conn = match connecting.into_0rtt() {
-            Ok((new_conn, _)) => new_conn,
-            Err(connecting) => connecting.await?,
-        };
+        let new_conn =
+            handshake(connecting, &self.client_auth, self.release_early)
+                .await?;
 
         let NewConnection {
             connection: x,
@@ -158,14 +194,32 @@ where
 pub struct RawAcceptor {
     lis: Incoming,
     addr: CommonAddr,
+    client_auth: Option<ClientAuth>,
+    release_early: bool,
 }
 
 impl RawAcceptor {
-    pub fn new(lis: Incoming, addr: CommonAddr) -> Self {
-        RawAcceptor { lis, addr }
+    pub fn new(
+        lis: Incoming,
+        addr: CommonAddr,
+        client_auth: Option<ClientAuth>,
+        release_early: bool,
+    ) -> Self {
+        RawAcceptor {
+            lis,
+            addr,
+            client_auth,
+            release_early,
+        }
     }
     pub fn set_connector<C>(self, cc: Arc<C>) -> Acceptor<C> {
-        Acceptor::new(cc, self.lis, self.addr)
+        Acceptor::new(
+            cc,
+            self.lis,
+            self.addr,
+            self.client_auth,
+            self.release_early,
+        )
     }
 }
 
@@ -188,11 +242,9 @@ impl AsyncAccept for RawAcceptor {
             Error::new(ErrorKind::ConnectionAborted, "connection abort")
         })?;
 
-        // early data
-        let new_conn = match connecting.into_0rtt() {
-            Ok((new_conn, _)) => new_conn,
-            Err(connecting) => connecting.await?,
-        };
+        let new_conn =
+            handshake(connecting, &self.client_auth, self.release_early)
+                .await?;
 
         let NewConnection {
             connection: x,
@@ -1,34 +1,40 @@
 use std::io::{Result, Error, ErrorKind};
-use std::net::SocketAddr;
-use std::sync::RwLock;
+use std::sync::{Mutex, RwLock};
 use std::sync::atomic::{AtomicUsize, Ordering};
 
 use log::{debug, trace};
 use async_trait::async_trait;
+use futures::FutureExt;
 use quinn::crypto::rustls::TlsSession;
 use quinn::generic::Connection;
-use quinn::{Endpoint, NewConnection};
+use quinn::{ClientConfig, Endpoint, NewConnection};
 
-use super::QuicStream;
-use crate::dns;
-use crate::utils::CommonAddr;
+use super::{QuicStream, ZeroRtt};
+use crate::dns::{Resolver, Watcher};
+use crate::utils::{self, CommonAddr};
 use crate::transport::{AsyncConnect, Transport};
 
 pub struct Connector {
-    cc: Endpoint,
+    config: ClientConfig,
+    // ipv4, ipv6
+    endpoints: Mutex<[Option<Endpoint>; 2]>,
     addr: CommonAddr,
     sni: String,
     max_concurrent: usize,
     count: AtomicUsize,
-    channel: RwLock<Option<Connection<TlsSession>>>,
+    // reused streams wait for the handshake
+    channel: RwLock<Option<(Connection<TlsSession>, Option<ZeroRtt>)>>,
+    resolver: Resolver,
+    watcher: Watcher,
 }
 
 impl Connector {
     pub fn new(
-        cc: Endpoint,
+        config: ClientConfig,
         addr: CommonAddr,
         sni: String,
         max_concurrent: usize,
+        resolver: Resolver,
     ) -> Self {
         let max_concurrent = if max_concurrent == 0 || max_concurrent > 100 {
             100
@@ -36,14 +42,39 @@ impl Connector {
             max_concurrent
         };
         Connector {
-            cc,
+            config,
+            endpoints: Mutex::new([None, None]),
             addr,
             sni,
             max_concurrent,
             count: AtomicUsize::new(1),
             channel: RwLock::new(None),
+            watcher: Watcher::new(resolver.clone()),
+            resolver,
         }
     }
+
+    // bind an endpoint for each address family on demand
+    fn endpoint(&self, is_ipv6: bool) -> Result<Endpoint> {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let slot = &mut endpoints[is_ipv6 as usize];
+        if let Some(ep) = slot {
+            return Ok(ep.clone());
+        }
+        let bind_addr = if is_ipv6 {
+            utils::empty_sockaddr_v6()
+        } else {
+            utils::empty_sockaddr_v4()
+        };
+        let mut builder = Endpoint::builder();
+        builder.default_client_config(self.config.clone());
+        let (ep, _) = builder
+            .bind(&bind_addr)
+            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+        debug!("quic bind {}", &bind_addr);
+        *slot = Some(ep.clone());
+        Ok(ep)
+    }
 }
 
 #[async_trait]
@@ -57,54 +88,65 @@ impl AsyncConnect for Connector {
     #[inline]
     fn addr(&self) -> &CommonAddr { &self.addr }
 
+    #[inline]
+    fn resolver(&self) -> &Resolver { &self.resolver }
+
     #[inline]
     fn clear_reuse(&self) { *self.channel.write().unwrap() = None; }
 
     async fn connect(&self) -> Result<Self::IO> {
-        let client = new_client(self).await?;
+        let (client, zero_rtt) = new_client(self).await?;
         let (send, recv) = client.open_bi().await?;
-        Ok(QuicStream::new(send, recv))
+        Ok(match zero_rtt {
+            // the first payload is sent as 0-rtt
+            Some(accepted) => {
+                QuicStream::new_early(send, recv, client, accepted)
+            }
+            None => QuicStream::new(send, recv),
+        })
     }
 }
 
-async fn new_client(cc: &Connector) -> Result<Connection<TlsSession>> {
+// returns the 0-rtt state if the handshake is not complete
+async fn new_client(
+    cc: &Connector,
+) -> Result<(Connection<TlsSession>, Option<ZeroRtt>)> {
     // reuse existed connection
     trace!("quic init new client");
-    let channel = (*cc.channel.read().unwrap()).clone();
-    if let Some(client) = channel {
+    let mut channel = (*cc.channel.read().unwrap()).clone();
+    // retire the connection if the remote has moved
+    if channel.is_some() && cc.watcher.changed(&cc.addr).await {
+        debug!("quic retire connection -> {}", &cc.addr);
+        cc.clear_reuse();
+        channel = None;
+    }
+    if let Some((client, zero_rtt)) = channel {
         let count = cc.count.load(Ordering::Relaxed);
         trace!("quic reusable, current mux = {}", count);
         if count < cc.max_concurrent {
             debug!("quic connect[reuse {}] -> {}", count, &cc.addr);
             cc.count.fetch_add(1, Ordering::Relaxed);
-            return Ok(client);
+            if let Some(zero_rtt) = zero_rtt {
+                zero_rtt.await;
+            }
+            return Ok((client, None));
         };
     };
 
     // establish a new connection
-    let connect_addr = match &cc.addr {
-        CommonAddr::SocketAddr(sockaddr) => *sockaddr,
-        CommonAddr::DomainName(addr, port) => {
-            let ip = dns::resolve_async(addr).await?;
-            SocketAddr::new(ip, *port)
-        }
-        #[cfg(all(unix, feature = "uds"))]
-        CommonAddr::UnixSocketPath(_) => unreachable!(),
-    };
+    cc.watcher.reset(&cc.addr).await;
+    let connect_addr = cc.resolver.resolve_addr(&cc.addr).await?;
 
     debug!("quic connect[new] -> {}", &cc.addr);
     let connecting = cc
-        .cc
+        .endpoint(connect_addr.is_ipv6())?
         .connect(&connect_addr, &cc.sni)
         .map_err(|e| Error::new(ErrorKind::ConnectionRefused, e))?;
 
-    // early data
-    let new_conn = match connecting.into_0rtt() {
-        Ok((new_conn, zero_rtt)) => {
-            zero_rtt.await;
-            new_conn
-        }
-        Err(connecting) => connecting.await?,
+    // early data, do not wait for the handshake
+    let (new_conn, zero_rtt) = match connecting.into_0rtt() {
+        Ok((new_conn, zero_rtt)) => (new_conn, Some(zero_rtt.shared())),
+        Err(connecting) => (connecting.await?, None),
     };
 
     let NewConnection {
@@ -114,6 +156,6 @@ async fn new_client(cc: &Connector) -> Result<Connection<TlsSession>> {
     // store connection
     // may have conflicts
     cc.count.store(1, Ordering::Relaxed);
-    *cc.channel.write().unwrap() = Some(client.clone());
-    Ok(client)
+    *cc.channel.write().unwrap() = Some((client.clone(), zero_rtt.clone()));
+    Ok((client, zero_rtt))
 }
@@ -2,6 +2,6 @@ mod stream;
 mod accept;
 mod connect;
 
-pub use stream::QuicStream;
+pub use stream::{QuicStream, ZeroRtt};
 pub use accept::{Acceptor, RawAcceptor};
 pub use connect::Connector;
@@ -1,16 +1,40 @@
 use std::pin::Pin;
+use std::future::Future;
 use std::task::{Poll, Context};
-use std::io::Result;
+use std::io::{Error, Result};
 
+use log::debug;
+use futures::ready;
+use futures::future::Shared;
 use tokio::io::{AsyncRead, AsyncWrite};
+use quinn::{ReadError, WriteError, ZeroRttAccepted};
 use quinn::crypto::rustls::TlsSession;
-use quinn::generic::{SendStream, RecvStream};
+use quinn::generic::{Connection, OpenBi, SendStream, RecvStream};
 
 use crate::transport::IOStream;
 
+pub type ZeroRtt = Shared<ZeroRttAccepted>;
+
 pub struct QuicStream {
     send: SendStream<TlsSession>,
     recv: RecvStream<TlsSession>,
+    early: Option<Box<EarlyData>>,
+}
+
+// a stream opened before the handshake completes
+struct EarlyData {
+    conn: Connection<TlsSession>,
+    accepted: ZeroRtt,
+    // written as 0-rtt, replayed on a new stream
+    // if the server rejects it
+    data: Vec<u8>,
+    state: EarlyState,
+}
+
+enum EarlyState {
+    Waiting,
+    Reopen(OpenBi<TlsSession>),
+    Replay(usize),
 }
 
 impl QuicStream {
@@ -19,46 +43,262 @@ impl QuicStream {
         send: SendStream<TlsSession>,
         recv: RecvStream<TlsSession>,
     ) -> Self {
-        QuicStream { send, recv }
+        QuicStream {
+            send,
+            recv,
+            early: None,
+        }
+    }
+
+    pub fn new_early(
+        send: SendStream<TlsSession>,
+        recv: RecvStream<TlsSession>,
+        conn: Connection<TlsSession>,
+        accepted: ZeroRtt,
+    ) -> Self {
+        QuicStream {
+            send,
+            recv,
+            early: Some(Box::new(EarlyData {
+                conn,
+                accepted,
+                data: Vec::new(),
+                state: EarlyState::Waiting,
+            })),
+        }
+    }
+
+    // drop the buffer once 0-rtt is accepted,
+    // or replay it if rejected
+    fn poll_early(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let early = match self.early.as_mut() {
+            Some(x) => x,
+            None => return Poll::Ready(Ok(())),
+        };
+        loop {
+            match early.state {
+                EarlyState::Waiting => {
+                    match Pin::new(&mut early.accepted).poll(cx) {
+                        Poll::Ready(true) => break,
+                        Poll::Ready(false) => early.reject(),
+                        // still handshaking
+                        Poll::Pending => return Poll::Ready(Ok(())),
+                    }
+                }
+                EarlyState::Reopen(ref mut open) => {
+                    let (send, recv) = ready!(Pin::new(open).poll(cx))?;
+                    self.send = send;
+                    self.recv = recv;
+                    early.state = EarlyState::Replay(0);
+                }
+                EarlyState::Replay(ref mut pos) => {
+                    while *pos < early.data.len() {
+                        *pos += ready!(Pin::new(&mut self.send)
+                            .poll_write(cx, &early.data[*pos..]))?;
+                    }
+                    break;
+                }
+            }
+        }
+        self.early = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl EarlyData {
+    fn reject(&mut self) {
+        if let EarlyState::Waiting = self.state {
+            debug!("quic 0-rtt rejected, replay {} bytes", self.data.len());
+            self.state = EarlyState::Reopen(self.conn.open_bi());
+        }
+    }
+}
+
+fn is_rejected(e: &Error) -> bool {
+    match e.get_ref() {
+        Some(x) => {
+            matches!(x.downcast_ref(), Some(WriteError::ZeroRttRejected))
+                || matches!(x.downcast_ref(), Some(ReadError::ZeroRttRejected))
+        }
+        None => false,
     }
 }
 
 impl IOStream for QuicStream {}
 
 impl AsyncRead for QuicStream {
-    #[inline]
     fn poll_read(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> Poll<Result<()>> {
-        Pin::new(&mut self.recv).poll_read(cx, buf)
+        loop {
+            ready!(self.poll_early(cx))?;
+            match Pin::new(&mut self.recv).poll_read(cx, buf) {
+                Poll::Ready(Err(e)) if is_rejected(&e) => {
+                    match self.early.as_mut() {
+                        Some(early) => early.reject(),
+                        None => return Poll::Ready(Err(e)),
+                    }
+                }
+                x => return x,
+            }
+        }
     }
 }
 
 impl AsyncWrite for QuicStream {
-    #[inline]
     fn poll_write(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize>> {
-        Pin::new(&mut self.send).poll_write(cx, buf)
+        ready!(self.poll_early(cx))?;
+        let this = &mut *self;
+        let early = match this.early.as_mut() {
+            Some(x) => x,
+            None => return Pin::new(&mut this.send).poll_write(cx, buf),
+        };
+        let n = match Pin::new(&mut this.send).poll_write(cx, buf) {
+            Poll::Ready(Ok(n)) => n,
+            // sent later with the replay
+            Poll::Ready(Err(e)) if is_rejected(&e) => {
+                early.reject();
+                buf.len()
+            }
+            x => return x,
+        };
+        early.data.extend_from_slice(&buf[..n]);
+        if let Poll::Ready(Err(e)) = this.poll_early(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
     }
 
-    #[inline]
     fn poll_flush(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<()>> {
+        ready!(self.poll_early(cx))?;
         Pin::new(&mut self.send).poll_flush(cx)
     }
 
-    #[inline]
     fn poll_shutdown(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<()>> {
+        // do not finish the stream before 0-rtt is settled,
+        // a pending early state is woken by the handshake
+        ready!(self.poll_early(cx))?;
+        if self.early.is_some() {
+            return Poll::Pending;
+        }
         Pin::new(&mut self.send).poll_shutdown(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use futures::{FutureExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use quinn::{Certificate, CertificateChain, PrivateKey};
+    use quinn::{ClientConfigBuilder, ServerConfigBuilder};
+    use quinn::{Endpoint, Incoming, NewConnection};
+
+    fn local() -> SocketAddr { "127.0.0.1:0".parse().unwrap() }
+
+    // each server has its own session cache,
+    // so a session from another one is not resumed
+    fn server(cert: &rcgen::Certificate) -> (Endpoint, Incoming) {
+        let der = cert.serialize_der().unwrap();
+        let chain =
+            CertificateChain::from_certs(vec![
+                Certificate::from_der(&der).unwrap()
+            ]);
+        let key =
+            PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
+        let mut config = ServerConfigBuilder::default();
+        config.certificate(chain, key).unwrap();
+        let mut builder = Endpoint::builder();
+        builder.listen(config.build());
+        builder.bind(&local()).unwrap()
+    }
+
+    // echo the first stream of the first connection
+    async fn echo(mut incoming: Incoming) {
+        // the connection is closed once its handle is dropped
+        let NewConnection {
+            connection: _conn,
+            mut bi_streams,
+            ..
+        } = incoming.next().await.unwrap().await.unwrap();
+        let (mut send, recv) = bi_streams.next().await.unwrap().unwrap();
+        let buf = recv.read_to_end(1024).await.unwrap();
+        send.write_all(&buf).await.unwrap();
+        send.finish().await.unwrap();
+    }
+
+    #[test]
+    #[ignore = "quinn 0.7 misreads peer addresses since rust 1.64"]
+    fn replay_rejected_early_data() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let fut = async {
+            let cert =
+                rcgen::generate_simple_self_signed(vec!["localhost".into()])
+                    .unwrap();
+            let der = cert.serialize_der().unwrap();
+            let mut config = ClientConfigBuilder::default();
+            config
+                .add_certificate_authority(Certificate::from_der(&der).unwrap())
+                .unwrap();
+            config.enable_0rtt();
+            let mut builder = Endpoint::builder();
+            builder.default_client_config(config.build());
+            let (client, _) = builder.bind(&local()).unwrap();
+
+            // a full handshake for the session ticket
+            let (ep, incoming) = server(&cert);
+            let addr = ep.local_addr().unwrap();
+            tokio::spawn(echo(incoming));
+            let NewConnection { connection, .. } =
+                client.connect(&addr, "localhost").unwrap().await.unwrap();
+            let (mut send, recv) = connection.open_bi().await.unwrap();
+            send.write_all(b"ticket").await.unwrap();
+            send.finish().await.unwrap();
+            assert_eq!(recv.read_to_end(1024).await.unwrap(), b"ticket");
+            connection.close(0u32.into(), b"");
+
+            // resume against another server, which rejects 0-rtt
+            let (ep, incoming) = server(&cert);
+            let addr = ep.local_addr().unwrap();
+            tokio::spawn(echo(incoming));
+            let connecting = client.connect(&addr, "localhost").unwrap();
+            let (new_conn, accepted) = match connecting.into_0rtt() {
+                Ok(x) => x,
+                Err(_) => panic!("no 0-rtt session"),
+            };
+            let accepted = accepted.shared();
+            let conn = new_conn.connection;
+            let (send, recv) = conn.open_bi().await.unwrap();
+            let mut stream =
+                QuicStream::new_early(send, recv, conn, accepted.clone());
+            stream.write_all(b"early data").await.unwrap();
+            stream.shutdown().await.unwrap();
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).await.unwrap();
+            assert!(!accepted.await);
+            assert_eq!(buf, b"early data");
+        };
+        rt.block_on(async {
+            tokio::time::timeout(Duration::from_secs(10), fut)
+                .await
+                .expect("timeout")
+        });
+    }
+}
@@ -0,0 +1,85 @@
+use std::collections::HashSet;
+
+use log::{info, warn};
+use futures::try_join;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::io::{copy, Rewind};
+use crate::utils::{ClientHelloInfo, match_server_name};
+use crate::transport::plain;
+use crate::transport::AsyncConnect;
+
+// Forward handshakes that are not for us to a decoy,
+// so that the listener looks like an ordinary site.
+pub struct Fallback {
+    conn: plain::Connector,
+    // certificate names, exact or wildcard,
+    // None if any name gets the default certificate
+    names: Option<HashSet<String>>,
+    // whether a client without sni gets a certificate
+    has_default: bool,
+    alpns: Vec<Vec<u8>>,
+}
+
+impl Fallback {
+    pub fn new(
+        conn: plain::Connector,
+        names: Option<HashSet<String>>,
+        has_default: bool,
+        alpns: Vec<Vec<u8>>,
+    ) -> Self {
+        Fallback {
+            conn,
+            names,
+            has_default,
+            alpns,
+        }
+    }
+
+    // the reason if the hello does not match
+    pub fn check(
+        &self,
+        hello: Option<&ClientHelloInfo>,
+    ) -> Option<&'static str> {
+        let hello = match hello {
+            Some(x) => x,
+            None => return Some("not tls"),
+        };
+        match (&hello.sni, &self.names) {
+            (None, _) if !self.has_default => return Some("no sni"),
+            (Some(name), Some(names)) if !match_server_name(names, name) => {
+                return Some("unknown sni")
+            }
+            _ => {}
+        }
+        if !self.alpns.is_empty()
+            && !hello.alpns.is_empty()
+            && !hello.alpns.iter().any(|x| self.alpns.contains(x))
+        {
+            return Some("unknown alpn");
+        }
+        None
+    }
+
+    pub fn connector(&self) -> plain::Connector { self.conn.clone() }
+}
+
+// splice the connection to the decoy, replaying what is read
+pub async fn forward<S>(conn: plain::Connector, sin: Rewind<S>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let sout = match conn.connect().await {
+        Ok(sout) => sout,
+        Err(e) => {
+            warn!("tls fallback: failed to connect, {}", e);
+            return;
+        }
+    };
+    info!("tls fallback -> {}", conn.addr());
+    let (ri, wi) = tokio::io::split(sin);
+    let (ro, wo) = tokio::io::split(sout);
+    if let Err(e) = try_join!(copy(ri, wo), copy(ro, wi)) {
+        warn!("tls fallback: forwarding finished, {}", e);
+    }
+}
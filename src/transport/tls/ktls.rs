@@ -0,0 +1,577 @@
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::io::{Error, ErrorKind, Result};
+
+use log::debug;
+use lazy_static::lazy_static;
+use tokio::io::{AsyncWriteExt, Interest};
+use ring::hkdf;
+use rustls::{ServerConfig, ServerSession, Session, KeyLog};
+use rustls::{CipherSuite, ProtocolVersion};
+
+use super::Acceptor;
+use crate::io::Rewind;
+use crate::utils;
+use crate::transport::AsyncAccept;
+use crate::transport::plain::{self, PlainStream, linux_ext};
+
+// linux/tls.h
+const SOL_TLS: libc::c_int = 282;
+const TLS_TX: libc::c_int = 1;
+const TLS_RX: libc::c_int = 2;
+const TLS_1_3_VERSION: u16 = 0x0304;
+const TLS_CIPHER_AES_GCM_128: u16 = 51;
+const TLS_CIPHER_AES_GCM_256: u16 = 52;
+const TLS_CIPHER_CHACHA20_POLY1305: u16 = 54;
+
+const CLIENT_SECRET: &str = "CLIENT_TRAFFIC_SECRET_0";
+const SERVER_SECRET: &str = "SERVER_TRAFFIC_SECRET_0";
+
+#[derive(Default)]
+struct Secrets {
+    client: Vec<u8>,
+    server: Vec<u8>,
+}
+
+lazy_static! {
+    // by client random, only for handshakes to be offloaded
+    static ref SECRETS: Mutex<HashMap<[u8; 32], Secrets>> =
+        Mutex::new(HashMap::new());
+}
+
+// Collect the traffic secrets, which rustls does not expose otherwise.
+pub struct OffloadKeyLog(pub Arc<dyn KeyLog>);
+
+impl KeyLog for OffloadKeyLog {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        if self.0.will_log(label) {
+            self.0.log(label, client_random, secret);
+        }
+        let mut secrets = SECRETS.lock().unwrap();
+        let entry = match secrets.get_mut(client_random) {
+            Some(x) => x,
+            None => return,
+        };
+        match label {
+            CLIENT_SECRET => entry.client = secret.to_vec(),
+            SERVER_SECRET => entry.server = secret.to_vec(),
+            _ => {}
+        }
+    }
+
+    fn will_log(&self, label: &str) -> bool {
+        matches!(label, CLIENT_SECRET | SERVER_SECRET) || self.0.will_log(label)
+    }
+}
+
+// tlsv1.3 suites the kernel could take over
+const OFFLOAD_SUITES: [CipherSuite; 3] = [
+    CipherSuite::TLS13_AES_128_GCM_SHA256,
+    CipherSuite::TLS13_AES_256_GCM_SHA384,
+    CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+];
+
+// The suites the kernel could take over, in both directions.
+// Tlsv1.3 needs linux 5.1, and chacha20-poly1305 5.11.
+pub fn probe_offload() -> Result<Vec<CipherSuite>> {
+    let mut suites = Vec::new();
+    let mut last_err = None;
+    for suite in OFFLOAD_SUITES.iter() {
+        match probe_suite(*suite) {
+            Ok(()) => suites.push(*suite),
+            Err(e) => {
+                debug!("ktls: {:?} is not supported, {}", suite, e);
+                last_err = Some(e);
+            }
+        }
+    }
+    match last_err {
+        Some(e) if suites.is_empty() => Err(e),
+        _ => Ok(suites),
+    }
+}
+
+// The ulp only attaches to a connected socket,
+// so try the keys on a loopback connection.
+fn probe_suite(suite: CipherSuite) -> Result<()> {
+    let lis = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let stream = std::net::TcpStream::connect(lis.local_addr()?)?;
+    let _peer = lis.accept()?;
+    let info = crypto_info(suite, &[0u8; 48], 0)?;
+    let fd = stream.as_raw_fd();
+    set_opt(fd, libc::SOL_TCP, libc::TCP_ULP, b"tls")?;
+    set_opt(fd, SOL_TLS, TLS_TX, &info)?;
+    set_opt(fd, SOL_TLS, TLS_RX, &info)
+}
+
+fn set_opt(fd: RawFd, level: i32, name: i32, value: &[u8]) -> Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            value.as_ptr() as *const libc::c_void,
+            value.len() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+struct Len(usize);
+
+impl hkdf::KeyType for Len {
+    fn len(&self) -> usize { self.0 }
+}
+
+// HKDF-Expand-Label with an empty context
+fn expand_label(
+    alg: hkdf::Algorithm,
+    secret: &[u8],
+    label: &[u8],
+    out: &mut [u8],
+) -> Result<()> {
+    let len = (out.len() as u16).to_be_bytes();
+    let label_len = [(b"tls13 ".len() + label.len()) as u8];
+    let info: [&[u8]; 5] = [&len, &label_len, b"tls13 ", label, &[0]];
+    hkdf::Prk::new_less_safe(alg, secret)
+        .expand(&info, Len(out.len()))
+        .and_then(|okm| okm.fill(out))
+        .map_err(|_| Error::new(ErrorKind::Other, "failed to derive keys"))
+}
+
+// struct tls12_crypto_info_*
+fn crypto_info(suite: CipherSuite, secret: &[u8], seq: u64) -> Result<Vec<u8>> {
+    let (cipher, alg, key_len, salt_len) = match suite {
+        CipherSuite::TLS13_AES_128_GCM_SHA256 => {
+            (TLS_CIPHER_AES_GCM_128, hkdf::HKDF_SHA256, 16, 4)
+        }
+        CipherSuite::TLS13_AES_256_GCM_SHA384 => {
+            (TLS_CIPHER_AES_GCM_256, hkdf::HKDF_SHA384, 32, 4)
+        }
+        CipherSuite::TLS13_CHACHA20_POLY1305_SHA256 => {
+            (TLS_CIPHER_CHACHA20_POLY1305, hkdf::HKDF_SHA256, 32, 0)
+        }
+        x => {
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!("unsupported cipher suite: {:?}", x),
+            ))
+        }
+    };
+    let mut key = vec![0u8; key_len];
+    let mut iv = [0u8; 12];
+    expand_label(alg, secret, b"key", &mut key)?;
+    expand_label(alg, secret, b"iv", &mut iv)?;
+    // iv, key, salt, rec_seq
+    let mut info = Vec::with_capacity(4 + 12 + key_len + 8);
+    info.extend_from_slice(&TLS_1_3_VERSION.to_ne_bytes());
+    info.extend_from_slice(&cipher.to_ne_bytes());
+    info.extend_from_slice(&iv[salt_len..]);
+    info.extend_from_slice(&key);
+    info.extend_from_slice(&iv[..salt_len]);
+    info.extend_from_slice(&seq.to_be_bytes());
+    Ok(info)
+}
+
+fn record_len(header: &[u8]) -> usize {
+    5 + u16::from_be_bytes([header[3], header[4]]) as usize
+}
+
+fn peek(fd: RawFd, buf: &mut [u8]) -> Result<usize> {
+    let n = unsafe {
+        libc::recv(
+            fd,
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            libc::MSG_PEEK | libc::MSG_DONTWAIT,
+        )
+    };
+    if n < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+// Take a record only once it is complete,
+// so that nothing after the handshake is read.
+async fn read_record(stream: &PlainStream, buf: &mut Vec<u8>) -> Result<()> {
+    let fd = stream.as_raw_fd();
+    loop {
+        let ready = linux_ext::ready(stream, Interest::READABLE).await?;
+        let res = linux_ext::try_io(stream, Interest::READABLE, || {
+            let mut header = [0u8; 5];
+            let n = peek(fd, &mut header)?;
+            if n == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            if n < header.len() {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            buf.resize(record_len(&header), 0);
+            if peek(fd, buf)? < buf.len() {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            let n = unsafe {
+                libc::recv(
+                    fd,
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    libc::MSG_DONTWAIT,
+                )
+            };
+            if n < 0 {
+                return Err(Error::last_os_error());
+            }
+            Ok(())
+        });
+        match res {
+            Ok(()) => return Ok(()),
+            // a partial record is never completed
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                if ready.is_read_closed() {
+                    return Err(ErrorKind::UnexpectedEof.into());
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn process(sess: &mut ServerSession, mut data: &[u8]) -> Result<()> {
+    while !data.is_empty() {
+        if sess.read_tls(&mut data)? == 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "record too large"));
+        }
+        sess.process_new_packets()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    }
+    Ok(())
+}
+
+// Drive the handshake record by record, instead of tokio-rustls.
+// Returns the number of records sent after it, e.g. tickets.
+async fn handshake(
+    stream: &mut PlainStream,
+    prefix: &[u8],
+    sess: &mut ServerSession,
+) -> Result<u64> {
+    let mut res = process(sess, prefix);
+    let mut record = Vec::new();
+    loop {
+        // also flush the alert if any
+        let mut out = Vec::new();
+        while sess.wants_write() {
+            sess.write_tls(&mut out)?;
+        }
+        stream.write_all(&out).await?;
+        res?;
+        if !sess.is_handshaking() {
+            let mut sent = 0;
+            let mut rest = &out[..];
+            while rest.len() >= 5 {
+                rest = &rest[std::cmp::min(record_len(rest), rest.len())..];
+                sent += 1;
+            }
+            return Ok(sent);
+        }
+        read_record(stream, &mut record).await?;
+        res = process(sess, &record);
+    }
+}
+
+impl Acceptor<plain::Acceptor> {
+    // Complete the handshake, then leave the record layer to the kernel.
+    // The returned stream could be read and written as a raw one.
+    pub async fn accept_offload(
+        &self,
+        base: PlainStream,
+    ) -> Result<PlainStream> {
+        let (stream, tx, rx) = self.handshake_offload(base).await?;
+        let fd = stream.as_raw_fd();
+        set_opt(fd, libc::SOL_TCP, libc::TCP_ULP, b"tls")?;
+        set_opt(fd, SOL_TLS, TLS_TX, &tx)?;
+        set_opt(fd, SOL_TLS, TLS_RX, &rx)?;
+        Ok(stream)
+    }
+
+    // Returns the crypto info to send and receive with.
+    async fn handshake_offload(
+        &self,
+        base: PlainStream,
+    ) -> Result<(PlainStream, Vec<u8>, Vec<u8>)> {
+        let mut stream = self.lis.accept(base).await?;
+        debug!("ktls accept <-");
+        let mut buf = Vec::new();
+        let hello = utils::read_client_hello(&mut stream, &mut buf).await?;
+        let stream =
+            self.check_fallback(hello.as_ref(), Rewind::new(buf, stream))?;
+        let hello = hello
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "not tls"))?;
+        let (prefix, mut stream) = stream.into_parts();
+
+        let tlsc: Arc<ServerConfig> = self.tlsc.read().unwrap().clone();
+        let mut sess = ServerSession::new(&tlsc);
+        SECRETS
+            .lock()
+            .unwrap()
+            .insert(hello.random, Secrets::default());
+        let res = handshake(&mut stream, &prefix, &mut sess).await;
+        let secrets = SECRETS.lock().unwrap().remove(&hello.random);
+        let sent = res?;
+
+        if let Some(client_auth) = &self.client_auth {
+            client_auth.check(sess.get_peer_certificates().as_deref())?;
+        }
+        if sess.get_protocol_version() != Some(ProtocolVersion::TLSv1_3) {
+            return Err(Error::new(ErrorKind::Other, "ktls requires tlsv1.3"));
+        }
+        let suite = sess.get_negotiated_ciphersuite().unwrap().suite;
+        let secrets = match secrets {
+            Some(x) if !x.client.is_empty() && !x.server.is_empty() => x,
+            _ => {
+                return Err(Error::new(ErrorKind::Other, "no traffic secrets"))
+            }
+        };
+
+        // nothing is read after the handshake, tickets are sent
+        let tx = crypto_info(suite, &secrets.server, sent)?;
+        let rx = crypto_info(suite, &secrets.client, 0)?;
+        Ok((stream, tx, rx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::size_of;
+    use futures::join;
+    use ring::aead;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::TlsConnector;
+    use rustls::{ClientConfig, NoClientAuth, NoKeyLog};
+    use webpki::DNSNameRef;
+
+    use crate::utils::CommonAddr;
+    use crate::transport::plain::PlainListener;
+
+    fn unhex(x: &str) -> Vec<u8> {
+        (0..x.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&x[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // rfc 8448, simple 1-rtt handshake
+    const SERVER_HS_SECRET: &str =
+        "b67b7d690cc16c4e75e54213cb2d37b4e9c912bcded9105d42befd59d391ad38";
+    const SERVER_AP_SECRET: &str =
+        "a11af9f05531f856ad47116b45a950328204b4f44bfb6b3a4b4f1f3fcb631643";
+
+    #[test]
+    fn expand_traffic_keys() {
+        let vectors = [
+            (
+                SERVER_HS_SECRET,
+                "3fce516009c21727d0f2e4e86ee403bc",
+                "5d313eb2671276ee13000b30",
+            ),
+            (
+                SERVER_AP_SECRET,
+                "9f02283b6c9c07efc26bb9f2ac92e356",
+                "cf782b88dd83549aadf1e984",
+            ),
+        ];
+        for (secret, key, iv) in vectors.iter() {
+            let secret = unhex(secret);
+            let mut out = [0u8; 16];
+            expand_label(hkdf::HKDF_SHA256, &secret, b"key", &mut out).unwrap();
+            assert_eq!(&out[..], &unhex(key)[..]);
+            let mut out = [0u8; 12];
+            expand_label(hkdf::HKDF_SHA256, &secret, b"iv", &mut out).unwrap();
+            assert_eq!(&out[..], &unhex(iv)[..]);
+        }
+    }
+
+    fn as_bytes<T>(x: &T) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(
+                x as *const T as *const u8,
+                size_of::<T>(),
+            )
+        }
+    }
+
+    fn derive(
+        alg: hkdf::Algorithm,
+        secret: &[u8],
+        key_len: usize,
+    ) -> (Vec<u8>, [u8; 12]) {
+        let mut key = vec![0u8; key_len];
+        let mut iv = [0u8; 12];
+        expand_label(alg, secret, b"key", &mut key).unwrap();
+        expand_label(alg, secret, b"iv", &mut iv).unwrap();
+        (key, iv)
+    }
+
+    #[test]
+    fn crypto_info_layout() {
+        let secret = unhex(SERVER_AP_SECRET);
+        let seq = 0x0102_0304_0506_0708u64;
+
+        let mut info: libc::tls12_crypto_info_aes_gcm_128 =
+            unsafe { std::mem::zeroed() };
+        info.info.version = libc::TLS_1_3_VERSION;
+        info.info.cipher_type = libc::TLS_CIPHER_AES_GCM_128;
+        info.key
+            .copy_from_slice(&unhex("9f02283b6c9c07efc26bb9f2ac92e356"));
+        info.salt.copy_from_slice(&unhex("cf782b88"));
+        info.iv.copy_from_slice(&unhex("dd83549aadf1e984"));
+        info.rec_seq = seq.to_be_bytes();
+        let out =
+            crypto_info(CipherSuite::TLS13_AES_128_GCM_SHA256, &secret, seq)
+                .unwrap();
+        assert_eq!(out, as_bytes(&info));
+
+        let (key, iv) = derive(hkdf::HKDF_SHA384, &secret, 32);
+        let mut info: libc::tls12_crypto_info_aes_gcm_256 =
+            unsafe { std::mem::zeroed() };
+        info.info.version = libc::TLS_1_3_VERSION;
+        info.info.cipher_type = libc::TLS_CIPHER_AES_GCM_256;
+        info.key.copy_from_slice(&key);
+        info.salt.copy_from_slice(&iv[..4]);
+        info.iv.copy_from_slice(&iv[4..]);
+        info.rec_seq = seq.to_be_bytes();
+        let out =
+            crypto_info(CipherSuite::TLS13_AES_256_GCM_SHA384, &secret, seq)
+                .unwrap();
+        assert_eq!(out, as_bytes(&info));
+
+        let (key, iv) = derive(hkdf::HKDF_SHA256, &secret, 32);
+        let mut info: libc::tls12_crypto_info_chacha20_poly1305 =
+            unsafe { std::mem::zeroed() };
+        info.info.version = libc::TLS_1_3_VERSION;
+        info.info.cipher_type = libc::TLS_CIPHER_CHACHA20_POLY1305;
+        info.key.copy_from_slice(&key);
+        info.iv.copy_from_slice(&iv);
+        info.rec_seq = seq.to_be_bytes();
+        let out = crypto_info(
+            CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+            &secret,
+            seq,
+        )
+        .unwrap();
+        assert_eq!(out, as_bytes(&info));
+    }
+
+    // what the kernel does with tls12_crypto_info_aes_gcm_128
+    fn aead_key(info: &[u8]) -> (aead::LessSafeKey, [u8; 12]) {
+        let key =
+            aead::UnboundKey::new(&aead::AES_128_GCM, &info[12..28]).unwrap();
+        let mut nonce = [0u8; 12];
+        nonce[..4].copy_from_slice(&info[28..32]);
+        nonce[4..].copy_from_slice(&info[4..12]);
+        for (x, y) in nonce[4..].iter_mut().zip(&info[32..40]) {
+            *x ^= y;
+        }
+        (aead::LessSafeKey::new(key), nonce)
+    }
+
+    fn seal(info: &[u8], data: &[u8]) -> Vec<u8> {
+        let (key, nonce) = aead_key(info);
+        let mut payload = data.to_vec();
+        payload.push(0x17);
+        let len = (payload.len() + key.algorithm().tag_len()) as u16;
+        let mut header = vec![0x17, 0x03, 0x03];
+        header.extend_from_slice(&len.to_be_bytes());
+        key.seal_in_place_append_tag(
+            aead::Nonce::assume_unique_for_key(nonce),
+            aead::Aad::from(&header),
+            &mut payload,
+        )
+        .unwrap();
+        header.extend_from_slice(&payload);
+        header
+    }
+
+    fn open(info: &[u8], record: &mut [u8]) -> Vec<u8> {
+        let (key, nonce) = aead_key(info);
+        let (header, payload) = record.split_at_mut(5);
+        let data = key
+            .open_in_place(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::from(&header),
+                payload,
+            )
+            .unwrap();
+        let (kind, data) = data.split_last().unwrap();
+        assert_eq!(*kind, 0x17);
+        data.to_vec()
+    }
+
+    #[test]
+    fn offload_after_tickets() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let cert =
+                rcgen::generate_simple_self_signed(vec!["localhost".into()])
+                    .unwrap();
+            let der = rustls::Certificate(cert.serialize_der().unwrap());
+            let key = rustls::PrivateKey(cert.serialize_private_key_der());
+
+            let mut server = ServerConfig::new(NoClientAuth::new());
+            server.set_single_cert(vec![der.clone()], key).unwrap();
+            server.versions = vec![ProtocolVersion::TLSv1_3];
+            server.ciphersuites =
+                vec![&rustls::ciphersuite::TLS13_AES_128_GCM_SHA256];
+            server.key_log = Arc::new(OffloadKeyLog(Arc::new(NoKeyLog {})));
+            let mut client = ClientConfig::new();
+            client.root_store.add(&der).unwrap();
+
+            let lis = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = lis.local_addr().unwrap();
+            let lis = plain::Acceptor::new(
+                PlainListener::TCP(lis),
+                CommonAddr::SocketAddr(addr),
+            );
+            let acceptor = Acceptor::new(lis, server, None, None);
+
+            let accept = async {
+                let (base, _) =
+                    acceptor.lis.inner().accept_plain().await.unwrap();
+                acceptor.handshake_offload(base).await.unwrap()
+            };
+            let connect = async {
+                let name = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+                let stream = TcpStream::connect(addr).await.unwrap();
+                TlsConnector::from(Arc::new(client))
+                    .connect(name, stream)
+                    .await
+                    .unwrap()
+            };
+            let ((mut stream, tx, rx), mut client) = join!(accept, connect);
+            // one ticket after the handshake
+            assert_eq!(&tx[32..40], &1u64.to_be_bytes());
+            assert_eq!(&rx[32..40], &0u64.to_be_bytes());
+
+            // sent like the kernel, after the ticket
+            stream.write_all(&seal(&tx, b"hello")).await.unwrap();
+            let mut buf = [0u8; 5];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+
+            client.write_all(b"world").await.unwrap();
+            client.flush().await.unwrap();
+            let mut record = Vec::new();
+            read_record(&stream, &mut record).await.unwrap();
+            assert_eq!(open(&rx, &mut record), b"world");
+        });
+    }
+}
@@ -1,32 +1,51 @@
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use log::debug;
 use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite};
 use webpki::DNSName;
-use rustls::{ClientConfig, ServerConfig};
+use rustls::{ClientConfig, ServerConfig, Session};
 use tokio_rustls::{TlsAcceptor, TlsConnector};
 // re-export
-pub use tokio_rustls::client::TlsStream as ClientTLSStream;
 pub use tokio_rustls::server::TlsStream as ServerTLSStream;
 
+mod stream;
+pub use stream::ClientTLSStream;
+
+mod fallback;
+pub use fallback::Fallback;
+
+#[cfg(target_os = "linux")]
+mod ktls;
+#[cfg(target_os = "linux")]
+pub use ktls::{OffloadKeyLog, probe_offload};
+
 use super::{AsyncConnect, AsyncAccept, Transport};
-use crate::utils::{self, CommonAddr};
+use crate::dns::Resolver;
+use crate::io::Rewind;
+use crate::utils::{self, CommonAddr, ClientAuth, ClientHelloInfo};
 
 pub struct Connector<T: AsyncConnect> {
     cc: T,
     sni: DNSName,
-    // includes inner tls config
+    // includes inner tls config, and the session cache
+    // so that later connections could resume
     tls: TlsConnector,
+    // the server rejected early data once, stop sending it
+    rejected: Arc<AtomicBool>,
 }
 
 impl<T: AsyncConnect> Connector<T> {
     pub fn new(cc: T, sni: DNSName, tlsc: ClientConfig) -> Self {
+        let early_data = tlsc.enable_early_data;
         Self {
             cc,
             sni,
-            tls: TlsConnector::from(Arc::new(tlsc)),
+            tls: TlsConnector::from(Arc::new(tlsc)).early_data(early_data),
+            rejected: Arc::new(AtomicBool::new(false)),
         }
     }
 }
@@ -42,26 +61,75 @@ impl<T: AsyncConnect> AsyncConnect for Connector<T> {
     #[inline]
     fn addr(&self) -> &CommonAddr { self.cc.addr() }
 
+    #[inline]
+    fn resolver(&self) -> &Resolver { self.cc.resolver() }
+
     fn clear_reuse(&self) {}
 
     async fn connect(&self) -> Result<Self::IO> {
         let stream = self.cc.connect().await?;
         debug!("tls connect ->");
-        self.tls.connect(self.sni.as_ref(), stream).await
+        // returns before the handshake if early data could be sent
+        let stream = if self.rejected.load(Ordering::Relaxed) {
+            let tls = self.tls.clone().early_data(false);
+            tls.connect(self.sni.as_ref(), stream).await?
+        } else {
+            self.tls.connect(self.sni.as_ref(), stream).await?
+        };
+        Ok(ClientTLSStream::new(stream).on_reject(self.rejected.clone()))
     }
 }
 
 pub struct Acceptor<T: AsyncAccept> {
     lis: T,
-    // includes inner tls config
-    tls: TlsAcceptor,
+    // replaced on reload
+    tlsc: Arc<RwLock<Arc<ServerConfig>>>,
+    client_auth: Option<ClientAuth>,
+    fallback: Option<Fallback>,
 }
 
 impl<T: AsyncAccept> Acceptor<T> {
-    pub fn new(lis: T, tlsc: ServerConfig) -> Self {
+    pub fn new(
+        lis: T,
+        tlsc: ServerConfig,
+        client_auth: Option<ClientAuth>,
+        fallback: Option<Fallback>,
+    ) -> Self {
         Self {
             lis,
-            tls: TlsAcceptor::from(Arc::new(tlsc)),
+            tlsc: Arc::new(RwLock::new(Arc::new(tlsc))),
+            client_auth,
+            fallback,
+        }
+    }
+
+    // shared with the reloader
+    pub fn server_config(&self) -> Arc<RwLock<Arc<ServerConfig>>> {
+        self.tlsc.clone()
+    }
+
+    // forward to the fallback if the hello is not for us
+    fn check_fallback<S>(
+        &self,
+        hello: Option<&ClientHelloInfo>,
+        stream: Rewind<S>,
+    ) -> Result<Rewind<S>>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let fallback = match &self.fallback {
+            Some(x) => x,
+            None => return Ok(stream),
+        };
+        match fallback.check(hello) {
+            Some(reason) => {
+                tokio::spawn(fallback::forward(fallback.connector(), stream));
+                Err(Error::new(
+                    ErrorKind::Other,
+                    format!("tls fallback, {}", reason),
+                ))
+            }
+            None => Ok(stream),
         }
     }
 }
@@ -72,21 +140,42 @@ impl<T: AsyncAccept> AsyncAccept for Acceptor<T> {
 
     const SCHEME: &'static str = "tls";
 
-    type IO = ServerTLSStream<T::IO>;
+    type IO = ServerTLSStream<Rewind<T::IO>>;
 
     type Base = T::Base;
 
     #[inline]
     fn addr(&self) -> &utils::CommonAddr { self.lis.addr() }
 
+    #[inline]
+    fn accept_first(&self) -> bool {
+        self.fallback.is_some() || self.lis.accept_first()
+    }
+
     #[inline]
     async fn accept_base(&self) -> Result<(Self::Base, SocketAddr)> {
         self.lis.accept_base().await
     }
 
     async fn accept(&self, base: Self::Base) -> Result<Self::IO> {
-        let stream = self.lis.accept(base).await?;
+        let mut stream = self.lis.accept(base).await?;
         debug!("tls accept <-");
-        Ok(self.tls.accept(stream).await?)
+        // check the client hello before anything is sent
+        let stream = match &self.fallback {
+            Some(_) => {
+                let mut buf = Vec::new();
+                let hello =
+                    utils::read_client_hello(&mut stream, &mut buf).await?;
+                self.check_fallback(hello.as_ref(), Rewind::new(buf, stream))?
+            }
+            None => Rewind::new(Vec::new(), stream),
+        };
+        let tls = TlsAcceptor::from(self.tlsc.read().unwrap().clone());
+        let stream = tls.accept(stream).await?;
+        if let Some(client_auth) = &self.client_auth {
+            let certs = stream.get_ref().1.get_peer_certificates();
+            client_auth.check(certs.as_deref())?;
+        }
+        Ok(stream)
     }
 }
@@ -0,0 +1,359 @@
+use std::pin::Pin;
+use std::task::{Poll, Context, Waker};
+use std::io::Result;
+use std::future::Future;
+use std::time::Duration;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use futures::ready;
+use rustls::Session;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Sleep};
+use tokio_rustls::client::TlsStream;
+
+use crate::transport::IOStream;
+
+// how long a read waits for early data to send
+const EARLY_DATA_WAIT: Duration = Duration::from_millis(50);
+
+enum State {
+    // nothing written, the client hello is held back
+    // so that it could carry the first payload
+    EarlyData,
+    // early data sent, the handshake is not complete
+    Handshaking,
+    Stream,
+}
+
+pub struct ClientTLSStream<T> {
+    io: TlsStream<T>,
+    state: State,
+    // reads wait for the first write, but not too long
+    reader: Option<Waker>,
+    timer: Option<Pin<Box<Sleep>>>,
+    // set if the server turns early data down
+    rejected: Option<Arc<AtomicBool>>,
+}
+
+impl<T> ClientTLSStream<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    // the handshake is skipped only if the resumed ticket allows
+    // early data, i.e. the server said it would accept it
+    pub fn new(io: TlsStream<T>) -> Self {
+        let state = if io.get_ref().1.is_handshaking() {
+            State::EarlyData
+        } else {
+            State::Stream
+        };
+        ClientTLSStream {
+            io,
+            state,
+            reader: None,
+            timer: None,
+            rejected: None,
+        }
+    }
+
+    // report a rejection, so that later connections
+    // do not hold the client hello back for nothing
+    pub fn on_reject(mut self, rejected: Arc<AtomicBool>) -> Self {
+        self.rejected = Some(rejected);
+        self
+    }
+
+    // send the client hello, complete the handshake,
+    // and resend early data if the server rejected it
+    fn poll_handshake(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        if !matches!(self.state, State::Stream) {
+            ready!(Pin::new(&mut self.io).poll_flush(cx))?;
+            self.state = State::Stream;
+            if !self.io.get_ref().1.is_early_data_accepted() {
+                if let Some(rejected) = &self.rejected {
+                    rejected.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn wake_reader(&mut self) {
+        if let Some(waker) = self.reader.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> IOStream for ClientTLSStream<T> where
+    T: AsyncRead + AsyncWrite + Send + Sync + Unpin
+{
+}
+
+impl<T> AsyncRead for ClientTLSStream<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        if let State::EarlyData = self.state {
+            self.reader = Some(cx.waker().clone());
+            let timer = self
+                .timer
+                .get_or_insert_with(|| Box::pin(sleep(EARLY_DATA_WAIT)));
+            ready!(timer.as_mut().poll(cx));
+            // nothing to send early, e.g. the server speaks first,
+            // so the client hello goes out alone
+            self.state = State::Handshaking;
+        }
+        ready!(self.poll_handshake(cx))?;
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl<T> AsyncWrite for ClientTLSStream<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        match self.state {
+            State::EarlyData => {
+                let n = ready!(Pin::new(&mut self.io).poll_write(cx, buf))?;
+                self.state = State::Handshaking;
+                // the client hello goes out with early data,
+                // later reads or writes finish the handshake
+                if let Poll::Ready(Err(e)) = self.poll_handshake(cx) {
+                    return Poll::Ready(Err(e));
+                }
+                self.wake_reader();
+                Poll::Ready(Ok(n))
+            }
+            State::Handshaking => {
+                ready!(self.poll_handshake(cx))?;
+                Pin::new(&mut self.io).poll_write(cx, buf)
+            }
+            State::Stream => Pin::new(&mut self.io).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<()>> {
+        ready!(self.poll_handshake(cx))?;
+        self.wake_reader();
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<()>> {
+        // make sure early data is delivered
+        if let State::Handshaking = self.state {
+            ready!(self.poll_handshake(cx))?;
+        }
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use futures::join;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio::time::Instant;
+    use tokio_rustls::{TlsAcceptor, TlsConnector, Connect};
+    use rustls::{ClientConfig, ServerConfig, NoClientAuth, ProtocolVersion};
+    use rustls::{StoresClientSessions, ClientSessionMemoryCache};
+    use rustls::internal::msgs::codec::Codec;
+    use rustls::internal::msgs::persist::ClientSessionValue;
+    use webpki::DNSNameRef;
+
+    use crate::io::Rewind;
+
+    // rustls only issues tickets for early data with quic,
+    // pretend the server allows it
+    struct AllowEarlyData(Arc<ClientSessionMemoryCache>);
+
+    impl StoresClientSessions for AllowEarlyData {
+        fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+            let value = match ClientSessionValue::read_bytes(&value) {
+                Some(mut x) => {
+                    x.max_early_data_size = 0x4000;
+                    x.get_encoding()
+                }
+                None => value,
+            };
+            self.0.put(key, value)
+        }
+
+        fn get(&self, key: &[u8]) -> Option<Vec<u8>> { self.0.get(key) }
+    }
+
+    fn configs() -> (TlsConnector, TlsAcceptor) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])
+            .unwrap();
+        let der = rustls::Certificate(cert.serialize_der().unwrap());
+        let key = rustls::PrivateKey(cert.serialize_private_key_der());
+
+        let mut server = ServerConfig::new(NoClientAuth::new());
+        server.set_single_cert(vec![der.clone()], key).unwrap();
+
+        let mut client = ClientConfig::new();
+        client.root_store.add(&der).unwrap();
+        client.versions = vec![ProtocolVersion::TLSv1_3];
+        client.enable_early_data = true;
+        client.session_persistence =
+            Arc::new(AllowEarlyData(ClientSessionMemoryCache::new(8)));
+
+        (
+            TlsConnector::from(Arc::new(client)).early_data(true),
+            TlsAcceptor::from(Arc::new(server)),
+        )
+    }
+
+    fn connect(
+        connector: &TlsConnector,
+    ) -> (Connect<DuplexStream>, DuplexStream) {
+        let (client, server) = tokio::io::duplex(0x10000);
+        let name = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        (connector.connect(name, client), server)
+    }
+
+    // content types of the records the client sent so far
+    async fn first_flight(server: &mut DuplexStream) -> (Vec<u8>, Vec<u8>) {
+        let mut buf = vec![0u8; 0x10000];
+        let n = server.read(&mut buf).await.unwrap();
+        buf.truncate(n);
+        let mut types = Vec::new();
+        let mut rest = &buf[..];
+        while rest.len() >= 5 {
+            let len = u16::from_be_bytes([rest[3], rest[4]]) as usize;
+            types.push(rest[0]);
+            rest = &rest[5 + len..];
+        }
+        (types, buf)
+    }
+
+    // skip the early data, like a server that rejects it
+    fn skip_early_data(buf: &[u8]) -> Vec<u8> {
+        let mut kept = Vec::new();
+        let mut rest = buf;
+        while rest.len() >= 5 {
+            let len = u16::from_be_bytes([rest[3], rest[4]]) as usize;
+            if rest[0] != 0x17 {
+                kept.extend_from_slice(&rest[..5 + len]);
+            }
+            rest = &rest[5 + len..];
+        }
+        kept
+    }
+
+    // a full handshake for the session ticket
+    async fn full_handshake(connector: &TlsConnector, acceptor: &TlsAcceptor) {
+        let (client, server) = connect(connector);
+        let (client, server) = join!(client, acceptor.accept(server));
+        let mut client = ClientTLSStream::new(client.unwrap());
+        assert!(matches!(client.state, State::Stream));
+        let serve = async {
+            let mut server = server.unwrap();
+            let mut buf = [0u8; 6];
+            server.read_exact(&mut buf).await.unwrap();
+            server.write_all(&buf).await.unwrap();
+            server.flush().await.unwrap();
+        };
+        let ticket = async {
+            client.write_all(b"ticket").await.unwrap();
+            let mut buf = [0u8; 6];
+            client.read_exact(&mut buf).await.unwrap();
+        };
+        join!(serve, ticket);
+    }
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .start_paused(true)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn resend_rejected_early_data() {
+        runtime().block_on(async {
+            let (connector, acceptor) = configs();
+            full_handshake(&connector, &acceptor).await;
+
+            // returns before the handshake
+            let (client, mut server) = connect(&connector);
+            let rejected = Arc::new(AtomicBool::new(false));
+            let mut client = ClientTLSStream::new(client.await.unwrap())
+                .on_reject(rejected.clone());
+            assert!(matches!(client.state, State::EarlyData));
+            let serve = async {
+                // the client hello carries the first write
+                let (types, buf) = first_flight(&mut server).await;
+                assert_eq!(types[0], 0x16);
+                assert!(types.contains(&0x17), "{:?}", types);
+                let stream = Rewind::new(skip_early_data(&buf), server);
+                let mut server = acceptor.accept(stream).await.unwrap();
+                let mut buf = [0u8; 5];
+                server.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello");
+                server.write_all(b"world").await.unwrap();
+                server.flush().await.unwrap();
+            };
+            let talk = async {
+                client.write_all(b"hello").await.unwrap();
+                let mut buf = [0u8; 5];
+                client.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"world");
+            };
+            join!(serve, talk);
+            // sent again after the handshake
+            assert!(!client.io.get_ref().1.is_early_data_accepted());
+            assert!(rejected.load(Ordering::Relaxed));
+        });
+    }
+
+    #[test]
+    fn read_before_early_data() {
+        runtime().block_on(async {
+            let (connector, acceptor) = configs();
+            full_handshake(&connector, &acceptor).await;
+
+            // returns before the handshake
+            let (client, mut server) = connect(&connector);
+            let mut client = ClientTLSStream::new(client.await.unwrap());
+            assert!(matches!(client.state, State::EarlyData));
+            let start = Instant::now();
+            let serve = async {
+                // the client hello goes out alone
+                let (types, buf) = first_flight(&mut server).await;
+                assert!(start.elapsed() >= EARLY_DATA_WAIT);
+                assert!(!types.contains(&0x17), "{:?}", types);
+                let stream = Rewind::new(buf, server);
+                let mut server = acceptor.accept(stream).await.unwrap();
+                server.write_all(b"banner").await.unwrap();
+                server.flush().await.unwrap();
+            };
+            // the server speaks first
+            let listen = async {
+                let mut buf = [0u8; 6];
+                client.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"banner");
+            };
+            join!(serve, listen);
+        });
+    }
+}
@@ -1,5 +1,4 @@
 use std::io;
-use std::net::SocketAddr;
 
 use log::debug;
 use tokio::net::UdpSocket;
@@ -7,15 +6,18 @@ use async_trait::async_trait;
 
 use super::UdpClientStream;
 use crate::transport::{AsyncConnect, Transport};
-use crate::dns;
+use crate::dns::Resolver;
 use crate::utils::{self, CommonAddr};
 
 pub struct Connector {
     addr: CommonAddr,
+    resolver: Resolver,
 }
 
 impl Connector {
-    pub fn new(addr: CommonAddr) -> Self { Connector { addr } }
+    pub fn new(addr: CommonAddr, resolver: Resolver) -> Self {
+        Connector { addr, resolver }
+    }
 }
 
 #[async_trait]
@@ -29,18 +31,13 @@ impl AsyncConnect for Connector {
     #[inline]
     fn addr(&self) -> &CommonAddr { &self.addr }
 
+    #[inline]
+    fn resolver(&self) -> &Resolver { &self.resolver }
+
     fn clear_reuse(&self) {}
 
     async fn connect(&self) -> io::Result<Self::IO> {
-        let connect_addr = match &self.addr {
-            CommonAddr::SocketAddr(sockaddr) => *sockaddr,
-            CommonAddr::DomainName(addr, port) => {
-                let ip = dns::resolve_async(addr).await?;
-                SocketAddr::new(ip, *port)
-            }
-            #[cfg(all(unix, feature = "uds"))]
-            CommonAddr::UnixSocketPath(_) => unreachable!(),
-        };
+        let connect_addr = self.resolver.resolve_addr(&self.addr).await?;
         let bind_addr = if connect_addr.is_ipv4() {
             utils::empty_sockaddr_v4()
         } else {
@@ -67,6 +67,9 @@ impl<T: AsyncAccept> AsyncAccept for Acceptor<T> {
     #[inline]
     fn addr(&self) -> &CommonAddr { self.lis.addr() }
 
+    #[inline]
+    fn accept_first(&self) -> bool { self.lis.accept_first() }
+
     #[inline]
     async fn accept_base(&self) -> Result<(Self::Base, SocketAddr)> {
         self.lis.accept_base().await
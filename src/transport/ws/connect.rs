@@ -10,6 +10,7 @@ use tungstenite::protocol::WebSocketConfig;
 use super::WebSocketStream;
 use crate::transport::{AsyncConnect, Transport};
 
+use crate::dns::Resolver;
 use crate::utils::CommonAddr;
 
 pub struct Connector<T: AsyncConnect> {
@@ -48,6 +49,9 @@ impl<T: AsyncConnect> AsyncConnect for Connector<T> {
     #[inline]
     fn addr(&self) -> &CommonAddr { self.cc.addr() }
 
+    #[inline]
+    fn resolver(&self) -> &Resolver { self.cc.resolver() }
+
     fn clear_reuse(&self) {}
 
     async fn connect(&self) -> Result<Self::IO> {
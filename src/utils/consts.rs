@@ -18,6 +18,12 @@ pub const PIPE_BUF_SIZE: usize = 0x10000;
 // timeout
 #[cfg(feature = "udp")]
 pub const UDP_TIMEOUT: u64 = 20;
+// clients that wait for the server, or stop in the middle
+pub const SNIFF_TIMEOUT: u64 = 1;
+
+// happy eyeballs attempt delay(ms)
+pub const HAPPY_EYEBALLS_DELAY: u64 = 250;
+pub const MIN_HAPPY_EYEBALLS_DELAY: u64 = 10;
 
 // others
 pub const NOT_A_DNS_NAME: &str = "localhost";
@@ -0,0 +1,321 @@
+use std::io::{Error, ErrorKind, Result};
+use std::collections::HashSet;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::timeout;
+
+use super::consts::SNIFF_TIMEOUT;
+
+// a client hello larger than this is not expected
+const MAX_HELLO_SIZE: usize = 0x10000;
+
+// Fields of a tls client hello, read without decrypting anything.
+pub struct ClientHelloInfo {
+    pub random: [u8; 32],
+    pub sni: Option<String>,
+    pub alpns: Vec<Vec<u8>>,
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (x, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(x)
+    }
+
+    fn u8(&mut self) -> Option<usize> { self.take(1).map(|x| x[0] as usize) }
+
+    fn u16(&mut self) -> Option<usize> {
+        self.take(2)
+            .map(|x| u16::from_be_bytes([x[0], x[1]]) as usize)
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|x| u32::from_be_bytes([0, x[0], x[1], x[2]]) as usize)
+    }
+
+    // a vector prefixed with its length
+    fn vec8(&mut self) -> Option<Reader<'a>> {
+        let n = self.u8()?;
+        self.take(n).map(Reader)
+    }
+
+    fn vec16(&mut self) -> Option<Reader<'a>> {
+        let n = self.u16()?;
+        self.take(n).map(Reader)
+    }
+
+    fn is_empty(&self) -> bool { self.0.is_empty() }
+}
+
+fn not_tls() -> Error { Error::new(ErrorKind::InvalidData, "not tls") }
+
+// Ok(None) if more bytes are needed,
+// the hello may span several records
+pub fn parse_client_hello(buf: &[u8]) -> Result<Option<ClientHelloInfo>> {
+    let mut records = Reader(buf);
+    let mut handshake = Vec::new();
+    loop {
+        // handshake record, ssl3 or later
+        match records.0 {
+            [] => return Ok(None),
+            [0x16] | [0x16, 0x03, ..] => {}
+            _ => return Err(not_tls()),
+        }
+        let header = match records.take(5) {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+        match records.take(len) {
+            Some(x) => handshake.extend_from_slice(x),
+            None => return Ok(None),
+        }
+        let mut msg = Reader(&handshake);
+        // client hello
+        if matches!(msg.u8(), Some(x) if x != 0x01) {
+            return Err(not_tls());
+        }
+        let len = match msg.u24() {
+            Some(x) => x,
+            None => continue,
+        };
+        if len > MAX_HELLO_SIZE {
+            return Err(not_tls());
+        }
+        if let Some(body) = msg.take(len) {
+            return parse_hello_body(body).ok_or_else(not_tls).map(Some);
+        }
+    }
+}
+
+fn parse_hello_body(body: &[u8]) -> Option<ClientHelloInfo> {
+    let mut r = Reader(body);
+    // version, random
+    r.take(2)?;
+    let mut random = [0u8; 32];
+    random.copy_from_slice(r.take(32)?);
+    // session id, cipher suites, compression methods
+    r.vec8()?;
+    r.vec16()?;
+    r.vec8()?;
+
+    let mut hello = ClientHelloInfo {
+        random,
+        sni: None,
+        alpns: Vec::new(),
+    };
+    if r.is_empty() {
+        return Some(hello);
+    }
+    let mut exts = r.vec16()?;
+    while !exts.is_empty() {
+        let ext_type = exts.u16()?;
+        let mut ext = exts.vec16()?;
+        match ext_type {
+            // server name
+            0 => {
+                let mut names = ext.vec16()?;
+                while !names.is_empty() {
+                    let name_type = names.u8()?;
+                    let name = names.vec16()?;
+                    if name_type == 0 {
+                        let name = std::str::from_utf8(name.0).ok()?;
+                        hello.sni = Some(name.to_ascii_lowercase());
+                    }
+                }
+            }
+            // alpn
+            16 => {
+                let mut protos = ext.vec16()?;
+                while !protos.is_empty() {
+                    hello.alpns.push(protos.vec8()?.0.to_vec());
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(hello)
+}
+
+// Read until the client hello is complete, the bytes are kept in buf.
+// Returns None if this is not tls, or the hello is not sent in time.
+pub async fn read_client_hello<S>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+) -> Result<Option<ClientHelloInfo>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut chunk = vec![0u8; 4096];
+    let read_all = async {
+        loop {
+            match parse_client_hello(buf) {
+                Ok(Some(hello)) => return Ok(Some(hello)),
+                Ok(None) if buf.len() < MAX_HELLO_SIZE => {}
+                _ => return Ok(None),
+            }
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                return Ok(None);
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        }
+    };
+    timeout(Duration::from_secs(SNIFF_TIMEOUT), read_all)
+        .await
+        .unwrap_or(Ok(None))
+}
+
+// exact names, or wildcards like *.example.com
+pub fn match_server_name(names: &HashSet<String>, name: &str) -> bool {
+    if names.contains(name) {
+        return true;
+    }
+    match name.split_once('.') {
+        Some((_, parent)) => names.contains(&format!("*.{}", parent)),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "tls")]
+    use std::sync::Arc;
+    use tokio::io::AsyncWriteExt;
+    #[cfg(feature = "tls")]
+    use rustls::{ClientConfig, ClientSession, Session};
+    #[cfg(feature = "tls")]
+    use webpki::DNSNameRef;
+
+    // the first flight of a rustls client
+    #[cfg(feature = "tls")]
+    fn client_hello(sni: bool, alpns: &[&[u8]]) -> Vec<u8> {
+        let mut config = ClientConfig::new();
+        config.enable_sni = sni;
+        config.alpn_protocols = alpns.iter().map(|x| x.to_vec()).collect();
+        let name = DNSNameRef::try_from_ascii_str("Example.com").unwrap();
+        let mut sess = ClientSession::new(&Arc::new(config), name);
+        let mut buf = Vec::new();
+        while sess.wants_write() {
+            sess.write_tls(&mut buf).unwrap();
+        }
+        buf
+    }
+
+    // the same handshake message in two records
+    #[cfg(feature = "tls")]
+    fn split_records(buf: &[u8], at: usize) -> Vec<u8> {
+        let msg = &buf[5..];
+        let mut out = Vec::new();
+        for part in [&msg[..at], &msg[at..]].iter() {
+            out.extend_from_slice(&buf[..3]);
+            out.extend_from_slice(&(part.len() as u16).to_be_bytes());
+            out.extend_from_slice(part);
+        }
+        out
+    }
+
+    #[cfg(feature = "tls")]
+    async fn read_from(buf: &[u8]) -> Option<ClientHelloInfo> {
+        let (mut client, mut server) = tokio::io::duplex(0x10000);
+        client.write_all(buf).await.unwrap();
+        drop(client);
+        let mut read = Vec::new();
+        let hello = read_client_hello(&mut server, &mut read).await.unwrap();
+        assert_eq!(read, buf);
+        hello
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn parse_rustls_hello() {
+        let buf = client_hello(true, &[b"h2", b"http/1.1"]);
+        let hello = parse_client_hello(&buf).unwrap().unwrap();
+        assert_eq!(hello.sni.as_deref(), Some("example.com"));
+        assert_eq!(hello.alpns, vec![b"h2".to_vec(), b"http/1.1".to_vec()]);
+        // record, handshake header, then version
+        assert_eq!(&hello.random[..], &buf[11..43]);
+
+        let buf = client_hello(false, &[]);
+        let hello = parse_client_hello(&buf).unwrap().unwrap();
+        assert!(hello.sni.is_none());
+        assert!(hello.alpns.is_empty());
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn parse_split_hello() {
+        let buf = client_hello(true, &[b"h2"]);
+        // in the middle of the handshake header, and of the body
+        for at in [2, 100].iter() {
+            let split = split_records(&buf, *at);
+            for n in 0..split.len() {
+                assert!(parse_client_hello(&split[..n]).unwrap().is_none());
+            }
+            let hello = parse_client_hello(&split).unwrap().unwrap();
+            assert_eq!(hello.sni.as_deref(), Some("example.com"));
+            assert_eq!(hello.alpns, vec![b"h2".to_vec()]);
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn parse_bad_hello() {
+        let buf = client_hello(true, &[b"h2"]);
+        // any byte could be wrong
+        for i in 0..buf.len() {
+            for x in [0x00, 0x7f, 0xff].iter() {
+                let mut bad = buf.clone();
+                bad[i] = *x;
+                let _ = parse_client_hello(&bad);
+            }
+        }
+        assert!(parse_client_hello(b"GET / HTTP/1.1\r\n").is_err());
+        // a record that is not a handshake
+        assert!(parse_client_hello(b"\x17\x03\x03\x00\x01\x00").is_err());
+        // a handshake that is not a client hello
+        assert!(parse_client_hello(b"\x16\x03\x03\x00\x01\x02").is_err());
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            assert_eq!(
+                read_from(&buf).await.unwrap().sni.as_deref(),
+                Some("example.com")
+            );
+            // closed in the middle
+            assert!(read_from(&buf[..buf.len() / 2]).await.is_none());
+            assert!(read_from(b"\x16\x03\x01\xff\xff\x01").await.is_none());
+            assert!(read_from(b"SSH-2.0-OpenSSH_8.9\r\n").await.is_none());
+        });
+    }
+
+    #[test]
+    fn hello_timeout() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .start_paused(true)
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            // the client stops in the middle, the bytes are kept
+            let (mut client, mut server) = tokio::io::duplex(64);
+            client.write_all(b"\x16\x03\x01\x02\x00").await.unwrap();
+            let mut buf = Vec::new();
+            let hello = read_client_hello(&mut server, &mut buf).await;
+            assert!(hello.unwrap().is_none());
+            assert_eq!(buf, b"\x16\x03\x01\x02\x00");
+        });
+    }
+}
@@ -14,6 +14,20 @@ pub use types::{CommonAddr, MaybeQuic};
 pub mod cert;
 #[cfg(feature = "tls")]
 pub use cert::{load_certs, load_keys, generate_cert_key, NATIVE_CERTS};
+#[cfg(feature = "tls")]
+pub use cert::{load_or_create_ca, issue_cert_key};
+#[cfg(feature = "tls")]
+pub use cert::{encode_certs_pem, encode_key_pem, write_key_file};
+#[cfg(feature = "tls")]
+pub use cert::ClientAuth;
+#[cfg(feature = "tls")]
+pub use cert::{cert_fingerprint, spki_fingerprint, check_key_pair};
+
+pub mod hello;
+pub use hello::{ClientHelloInfo, read_client_hello, match_server_name};
+
+pub mod sniff;
+pub use sniff::{Protocol, sniff};
 
 #[allow(clippy::mut_from_ref)]
 #[inline]
@@ -0,0 +1,154 @@
+use std::io::Result;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::timeout;
+
+use super::consts::SNIFF_TIMEOUT;
+
+// client connection preface of http2 with prior knowledge
+const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// request headers larger than this are not expected
+const MAX_HEADER_SIZE: usize = 0x2000;
+
+// longest method to wait for, e.g. OPTIONS
+const MAX_METHOD_SIZE: usize = 16;
+
+// What a client speaks, told from its first bytes.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    TLS,
+    // http2 over cleartext
+    H2C,
+    // http1 upgrade request, e.g. websocket
+    HTTP,
+    Other,
+}
+
+impl Protocol {
+    pub fn as_str(&self) -> &'static str {
+        use Protocol::*;
+        match self {
+            TLS => "tls",
+            H2C => "h2c",
+            HTTP => "http",
+            Other => "other",
+        }
+    }
+}
+
+// None if more bytes are needed
+pub fn classify(buf: &[u8]) -> Option<Protocol> {
+    match buf {
+        [] | [0x16] => None,
+        // handshake record, ssl3 or later
+        [0x16, 0x03, ..] => Some(Protocol::TLS),
+        _ if buf.starts_with(H2_PREFACE) => Some(Protocol::H2C),
+        _ if H2_PREFACE.starts_with(buf) => None,
+        _ => classify_http(buf),
+    }
+}
+
+fn classify_http(buf: &[u8]) -> Option<Protocol> {
+    // request line: METHOD SP target SP HTTP/1.x
+    let n = buf.iter().take_while(|x| x.is_ascii_uppercase()).count();
+    match buf.get(n) {
+        None if n < MAX_METHOD_SIZE => return None,
+        Some(b' ') if n > 0 => {}
+        _ => return Some(Protocol::Other),
+    }
+    // e.g. redis inline commands
+    let is_http1 =
+        |x: &str| x.ends_with(" HTTP/1.1") || x.ends_with(" HTTP/1.0");
+    if let Some(x) = buf.windows(2).position(|x| x == b"\r\n") {
+        if !is_http1(&String::from_utf8_lossy(&buf[..x])) {
+            return Some(Protocol::Other);
+        }
+    }
+    let end = match buf.windows(4).position(|x| x == b"\r\n\r\n") {
+        Some(x) => x,
+        None if buf.len() < MAX_HEADER_SIZE => return None,
+        None => return Some(Protocol::Other),
+    };
+    let head = String::from_utf8_lossy(&buf[..end]);
+    let mut lines = head.split("\r\n").skip(1);
+    let upgrade = lines.any(|x| {
+        matches!(x.split_once(':'), Some((k, _)) if k.trim().eq_ignore_ascii_case("upgrade"))
+    });
+    if upgrade {
+        Some(Protocol::HTTP)
+    } else {
+        Some(Protocol::Other)
+    }
+}
+
+// Read until the protocol is known, the bytes are kept in buf.
+// Other if the client sends nothing conclusive in time.
+pub async fn sniff<S>(stream: &mut S, buf: &mut Vec<u8>) -> Result<Protocol>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut chunk = vec![0u8; 4096];
+    let read_all = async {
+        loop {
+            if let Some(x) = classify(buf) {
+                return Ok(x);
+            }
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                return Ok(Protocol::Other);
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        }
+    };
+    timeout(Duration::from_secs(SNIFF_TIMEOUT), read_all)
+        .await
+        .unwrap_or(Ok(Protocol::Other))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn classify_first_bytes() {
+        let ws = b"GET / HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\n\r\n";
+        assert_eq!(classify(b"\x16\x03\x01\x02\x00"), Some(Protocol::TLS));
+        assert_eq!(classify(H2_PREFACE), Some(Protocol::H2C));
+        assert_eq!(classify(ws), Some(Protocol::HTTP));
+        assert_eq!(classify(b"GET / HTTP/1.1\r\n\r\n"), Some(Protocol::Other));
+        assert_eq!(classify(b"SET k v\r\n"), Some(Protocol::Other));
+        assert_eq!(classify(b"SSH-2.0-x\r\n"), Some(Protocol::Other));
+        // more bytes are needed
+        assert_eq!(classify(b""), None);
+        assert_eq!(classify(b"\x16"), None);
+        assert_eq!(classify(b"PRI * HT"), None);
+        assert_eq!(classify(b"GET / HTTP/1.1\r\nHost: x\r\n"), None);
+    }
+
+    #[test]
+    fn sniff_timeout() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .start_paused(true)
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            // the client waits for the server
+            let (mut client, mut server) = tokio::io::duplex(64);
+            let mut buf = Vec::new();
+            let protocol = sniff(&mut server, &mut buf).await.unwrap();
+            assert_eq!(protocol, Protocol::Other);
+            assert!(buf.is_empty());
+
+            // the client stops in the middle, the bytes are kept
+            client.write_all(b"GET / HT").await.unwrap();
+            let protocol = sniff(&mut server, &mut buf).await.unwrap();
+            assert_eq!(protocol, Protocol::Other);
+            assert_eq!(buf, b"GET / HT");
+        });
+    }
+}
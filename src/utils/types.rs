@@ -8,6 +8,8 @@ use std::path::PathBuf;
 pub enum CommonAddr {
     SocketAddr(SocketAddr),
     DomainName(String, u16),
+    // name of the service, srv+_service._proto.name
+    SrvName(String),
     #[cfg(all(unix, feature = "uds"))]
     UnixSocketPath(PathBuf),
 }
@@ -17,6 +19,7 @@ impl Display for CommonAddr {
         match self {
             Self::SocketAddr(sockaddr) => write!(f, "{}", sockaddr),
             Self::DomainName(addr, port) => write!(f, "{}:{}", addr, port),
+            Self::SrvName(name) => write!(f, "{}", name),
             #[cfg(all(unix, feature = "uds"))]
             Self::UnixSocketPath(path) => write!(f, "{}", path.display()),
         }